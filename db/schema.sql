@@ -29,3 +29,50 @@ CREATE TABLE documents_millis (
 CREATE INDEX doc_m_idx ON documents_millis USING gin (object jsonb_path_ops);
 CREATE INDEX fts_m_idx ON documents_millis USING gin (to_tsvector('english', (object ->> 'description'::text)));
 CREATE INDEX created_m_idx ON documents_millis ((object #> '{created}'));
+
+-- api keys; requests_per_minute and burst override the rate limiter defaults when set
+CREATE TABLE api_keys (
+    key text PRIMARY KEY,
+    name text,
+    requests_per_minute integer,
+    burst integer,
+    admin boolean NOT NULL DEFAULT false,
+    revoked boolean NOT NULL DEFAULT false
+);
+
+-- latest game update observed for every play of a game, backing sachet packets
+CREATE TABLE game_updates (
+    game_id uuid,
+    play_count bigint,
+    observed bigint,
+    game_complete boolean NOT NULL DEFAULT false,
+    data jsonb,
+    PRIMARY KEY (game_id, play_count)
+);
+
+-- bumped whenever a game's events or updates change, so cached sachet packets can be invalidated
+CREATE TABLE game_generations (
+    game_id uuid PRIMARY KEY,
+    generation bigint NOT NULL DEFAULT 0
+);
+
+-- precomputed season and day bounds (millis) for the /time endpoints, for sims the time map files don't cover
+CREATE TABLE time_map (
+    sim text,
+    season bigint,
+    day bigint,
+    start_time bigint NOT NULL,
+    end_time bigint NOT NULL,
+    PRIMARY KEY (sim, season, day)
+);
+
+-- first and last event (millis) of every day, kept up to date by the monitor and fill. existing
+-- data can be brought in with the backfill_time_bounds binary
+CREATE TABLE time_bounds (
+    sim text,
+    season bigint,
+    day bigint,
+    first_created bigint NOT NULL,
+    last_created bigint NOT NULL,
+    PRIMARY KEY (sim, season, day)
+);
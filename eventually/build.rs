@@ -0,0 +1,138 @@
+// generates the EventQuery setters for the search fields in schema.yaml, so the client takes the
+// same parameters the server does
+use serde_yaml::{Mapping, Value};
+use std::collections::HashSet;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+const KEYWORDS: &[&str] = &[
+    "type", "ref", "in", "for", "match", "mod", "move", "use", "where",
+];
+
+fn snake_case(name: &str) -> String {
+    let mut out = String::new();
+    for c in name.trim_start_matches('_').chars() {
+        if c.is_ascii_uppercase() {
+            out.push('_');
+            out.push(c.to_ascii_lowercase());
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            out.push(c);
+        }
+    }
+    out
+}
+
+// setters are named after the last part of the field's path, e.g. metadata.being is `being`
+fn method_name(field: &str) -> String {
+    let name = snake_case(field.rsplit('.').next().unwrap_or(field));
+    if KEYWORDS.contains(&name.as_str()) {
+        format!("event_{}", name)
+    } else {
+        name
+    }
+}
+
+struct Setters {
+    code: String,
+    names: HashSet<String>,
+}
+
+impl Setters {
+    fn add(&mut self, doc: &str, method: String, ty: &str, param: &str) {
+        if !self.names.insert(method.clone()) {
+            panic!(
+                "schema.yaml gives two query parameters the setter {}",
+                method
+            );
+        }
+        writeln!(self.code, "    /// {}", doc).unwrap();
+        writeln!(
+            self.code,
+            "    pub fn {}(self, value: {}) -> EventQuery {{\n        self.param({:?}, value)\n    }}\n",
+            method, ty, param
+        )
+        .unwrap();
+    }
+}
+
+fn str_of<'a>(map: &'a Mapping, key: &str) -> Option<&'a str> {
+    map.get(&Value::from(key)).and_then(|v| v.as_str())
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=schema.yaml");
+
+    let schema: Value = serde_yaml::from_str(
+        &fs::read_to_string("schema.yaml").expect("couldn't read schema.yaml"),
+    )
+    .expect("schema.yaml isn't valid yaml");
+    let fields = schema["fields"]
+        .as_mapping()
+        .expect("schema.yaml has no fields");
+
+    let mut setters = Setters {
+        code: String::new(),
+        names: HashSet::new(),
+    };
+    for (field, spec) in fields {
+        let field = field.as_str().expect("field names are strings");
+        let spec = match spec.as_mapping() {
+            Some(spec) => spec,
+            None => continue,
+        };
+        let doc = str_of(spec, "name").unwrap_or(field);
+
+        // ids are looked up through /versions, and converted fields (created) have setters that
+        // take the unconverted type, written by hand
+        if spec
+            .get(&Value::from("use_as_id"))
+            .and_then(|v| v.as_bool())
+            == Some(true)
+            || spec.contains_key(&Value::from("converter"))
+        {
+            continue;
+        }
+
+        let query = match spec.get(&Value::from("query")).and_then(|q| q.as_mapping()) {
+            Some(query) => query,
+            // fields without a query type are matched as tags
+            None => {
+                setters.add(doc, method_name(field), "&str", field);
+                continue;
+            }
+        };
+
+        match str_of(query, "type") {
+            Some("Range") => {
+                setters.add(doc, method_name(field), "i64", field);
+                for bound in &["min", "max"] {
+                    if let Some(param) = str_of(query, bound) {
+                        let doc = format!("{}, {}imum", doc, bound);
+                        setters.add(&doc, method_name(param), "i64", param);
+                    }
+                }
+            }
+            Some("NumericTag") => setters.add(doc, method_name(field), "i64", field),
+            Some("Bool") => setters.add(doc, method_name(field), "bool", field),
+            Some("Fulltext") => {
+                let method = match str_of(query, "syntax") {
+                    Some("WebSearch") => format!("{}_websearch", method_name(field)),
+                    _ => method_name(field),
+                };
+                setters.add(doc, method, "&str", field);
+            }
+            // nested fields only group the ones under them
+            Some("Nested") => {}
+            other => println!(
+                "cargo:warning=no EventQuery setter for {}, with query type {:?}",
+                field, other
+            ),
+        }
+    }
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("query_params.rs");
+    fs::write(out, format!("impl EventQuery {{\n{}}}\n", setters.code)).unwrap();
+}
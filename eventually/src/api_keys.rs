@@ -0,0 +1,151 @@
+use crate::*;
+use log::error;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::request::{self, FromRequest, Outcome};
+use rocket::{Orbit, Rocket};
+
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    pub key: String,
+    pub name: Option<String>,
+    pub requests_per_minute: Option<u32>,
+    pub burst: Option<u32>,
+    pub admin: bool,
+}
+
+struct CachedKey {
+    // None for keys that aren't in the table, so unknown keys don't hit postgres either
+    key: Option<ApiKey>,
+    fetched: Instant,
+    used: Instant,
+}
+
+// caches lookups against the api_keys table so we don't hit postgres on every request
+pub struct ApiKeyStore {
+    ttl: Duration,
+    max_entries: usize,
+    cache: Mutex<HashMap<String, CachedKey>>,
+}
+
+impl ApiKeyStore {
+    pub fn new(ttl: Duration, max_entries: usize) -> ApiKeyStore {
+        ApiKeyStore {
+            ttl,
+            max_entries,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Ok(None) means the key doesn't exist or was revoked; Err means we couldn't find out
+    pub async fn lookup(
+        &self,
+        rocket: &Rocket<Orbit>,
+        key: &str,
+    ) -> Result<Option<ApiKey>, EventuallyError> {
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .get_mut(key)
+            .filter(|entry| entry.fetched.elapsed() < self.ttl)
+            .map(|entry| {
+                entry.used = Instant::now();
+                entry.key.clone()
+            });
+
+        if let Some(entry) = cached {
+            return Ok(entry);
+        }
+
+        let conn = CompassConn::get_one(rocket)
+            .await
+            .ok_or(EventuallyError::NoDatabase)?;
+        let k = key.to_owned();
+        let row = conn
+            .run(move |c| {
+                c.query_opt(
+                    "SELECT key, name, requests_per_minute, burst, admin FROM api_keys WHERE key = $1 AND NOT revoked",
+                    &[&k],
+                )
+            })
+            .await;
+
+        let found = match row {
+            Ok(r) => r.map(|r| ApiKey {
+                key: r.get("key"),
+                name: r.get("name"),
+                requests_per_minute: r
+                    .get::<&str, Option<i32>>("requests_per_minute")
+                    .map(|v| v.max(0) as u32),
+                burst: r.get::<&str, Option<i32>>("burst").map(|v| v.max(0) as u32),
+                admin: r.get("admin"),
+            }),
+            Err(e) => {
+                // don't cache failures, the next request can retry
+                error!("couldn't look up api key: {}", e);
+                return Err(EventuallyError::NoDatabase);
+            }
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        if !cache.contains_key(key) && cache.len() >= self.max_entries {
+            evict(&mut cache, self.ttl, self.max_entries);
+        }
+        let now = Instant::now();
+        cache.insert(
+            key.to_owned(),
+            CachedKey {
+                key: found.clone(),
+                fetched: now,
+                used: now,
+            },
+        );
+
+        Ok(found)
+    }
+}
+
+// makes room for at least one more entry, the same way the rate limiter does for its buckets:
+// expired entries go first, then the least recently used, down to 90% of `max`
+fn evict(cache: &mut HashMap<String, CachedKey>, ttl: Duration, max: usize) {
+    cache.retain(|_, entry| entry.fetched.elapsed() < ttl);
+    if cache.len() < max {
+        return;
+    }
+
+    let keep = max.saturating_sub(max / 10 + 1);
+    let mut used = cache
+        .iter()
+        .map(|(key, entry)| (entry.used, key.clone()))
+        .collect::<Vec<_>>();
+    used.sort_unstable();
+    for (_, key) in used.into_iter().take(cache.len().saturating_sub(keep)) {
+        cache.remove(&key);
+    }
+}
+
+// request guard for endpoints that need an api key with the admin flag set
+pub struct AdminKey(pub ApiKey);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminKey {
+    type Error = EventuallyError;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let found = match (
+            req.headers().get_one(API_KEY_HEADER),
+            req.rocket().state::<ApiKeyStore>(),
+        ) {
+            (Some(key), Some(store)) => store.lookup(req.rocket(), key).await,
+            _ => Ok(None),
+        };
+
+        match found {
+            Ok(Some(key)) if key.admin => Outcome::Success(AdminKey(key)),
+            Ok(Some(_)) => Outcome::Failure((Status::Forbidden, EventuallyError::Forbidden)),
+            Ok(None) => Outcome::Failure((Status::Unauthorized, EventuallyError::Unauthorized)),
+            Err(e) => Outcome::Failure((Status::ServiceUnavailable, e)),
+        }
+    }
+}
@@ -3,15 +3,15 @@ use serde_json::json;
 use serde_json::Value as JSONValue;
 use uuid::Uuid;
 
-use rocket::get;
-use rocket::serde::json::Json as RocketJson;
+use rocket::{get, State};
 
 #[get("/count")]
 pub async fn count(
     raw_req: Query,
     db: CompassConn,
     schema: Schema,
-) -> Result<RocketJson<JSONValue>, CompassError> {
+    cache_config: &State<HttpCacheConfig>,
+) -> Result<Cached<JSONValue>, CompassError> {
     let mut req = raw_req.0;
 
     if let Some(before) = req.get_mut("before") {
@@ -32,8 +32,9 @@ pub async fn count(
         }
     }
 
-    db.run(move |c| json_count(c, &schema, &req).map(|v| RocketJson(json!({ "count": v }))))
-        .await
+    let settled = cache_config.is_settled(&req);
+    let count = db.run(move |c| json_count(c, &schema, &req)).await?;
+    Ok(cache_config.wrap(json!({ "count": count }), settled))
 }
 
 #[get("/events")]
@@ -41,7 +42,8 @@ pub async fn search(
     raw_req: Query,
     db: CompassConn,
     schema: Schema,
-) -> Result<RocketJson<Vec<JSONValue>>, CompassError> {
+    cache_config: &State<HttpCacheConfig>,
+) -> Result<Cached<Vec<JSONValue>>, CompassError> {
     let mut req = raw_req.0;
 
     if let Some(before) = req.get_mut("before") {
@@ -62,80 +64,178 @@ pub async fn search(
         }
     }
 
-    let expand_children = req
-        .remove("expand_children")
-        .and_then(|c| c.parse::<bool>().ok())
-        .unwrap_or(false);
-    let expand_parent = req
-        .remove("expand_parent")
-        .and_then(|c| c.parse::<bool>().ok())
-        .unwrap_or(false);
-    let expand_siblings = req
-        .remove("expand_siblings")
-        .and_then(|c| c.parse::<bool>().ok())
-        .unwrap_or(false);
+    let expansion = Expansion {
+        children: req
+            .remove("expand_children")
+            .and_then(|c| c.parse::<bool>().ok())
+            .unwrap_or(false),
+        parent: req
+            .remove("expand_parent")
+            .and_then(|c| c.parse::<bool>().ok())
+            .unwrap_or(false),
+        siblings: req
+            .remove("expand_siblings")
+            .and_then(|c| c.parse::<bool>().ok())
+            .unwrap_or(false),
+        depth: req
+            .remove("expand_depth")
+            .and_then(|c| c.parse::<usize>().ok())
+            .unwrap_or(1)
+            .clamp(1, MAX_EXPAND_DEPTH),
+    };
 
     let raw_query = req.remove("raw_query");
+    let settled = raw_query.is_none() && cache_config.is_settled(&req);
 
-    db.run(move |c| match json_search(c, &schema, &req, raw_query) {
-        Ok(v) => v
-            .into_iter()
-            .map(|mut event| {
-                if expand_children {
-                    if let Some(children) = event
-                        .get("metadata")
-                        .and_then(|i| i.get("children"))
-                        .and_then(|i| i.as_array())
-                    {
-                        event["metadata"]["children"] = json!(get_by_ids(
-                            c,
-                            &schema,
-                            &children
-                                .iter()
-                                .filter_map(|i| i.as_str())
-                                .filter_map(|i| Uuid::parse_str(i).ok())
-                                .collect()
-                        )?);
-                    }
-                }
+    db.run(move |c| -> Result<Vec<JSONValue>, CompassError> {
+        let mut events = json_search(c, &schema, &req, raw_query)?;
+        expand_events(c, &schema, &mut events, &expansion)?;
+        Ok(events)
+    })
+    .await
+    .map(|events| cache_config.wrap(events, settled))
+}
 
-                if expand_parent {
-                    if let Some(parent) = event
-                        .get("metadata")
-                        .and_then(|i| i.get("parent"))
-                        .and_then(|i| i.as_str())
-                        .and_then(|i| Uuid::parse_str(i).ok())
-                    {
-                        event["metadata"]["parent"] =
-                            json!(get_by_ids(c, &schema, &vec![parent])?.first());
-                    }
-                }
+const MAX_EXPAND_DEPTH: usize = 5;
 
-                if expand_siblings {
-                    if let Some(children) = event
-                        .get("metadata")
-                        .and_then(|i| i.get("siblingIds"))
-                        .and_then(|i| i.as_array())
-                    {
-                        event["metadata"]["_eventually_siblingEvents"] = json!(get_by_ids(
-                            c,
-                            &schema,
-                            &children
-                                .iter()
-                                .filter_map(|i| i.as_str())
-                                .filter_map(|i| Uuid::parse_str(i).ok())
-                                .collect()
-                        )?);
-                    }
-                }
+struct Expansion {
+    children: bool,
+    parent: bool,
+    siblings: bool,
+    // how many levels of children-of-children to expand
+    depth: usize,
+}
 
-                Ok(event)
-            })
-            .collect::<Result<Vec<JSONValue>, CompassError>>()
-            .map(RocketJson),
-        Err(e) => Err(e),
-    })
-    .await
+fn metadata_of(event: &JSONValue) -> EventMetadata {
+    event
+        .get("metadata")
+        .cloned()
+        .map(EventMetadata::from)
+        .unwrap_or_default()
+}
+
+fn event_id(event: &JSONValue) -> Option<Uuid> {
+    event
+        .get("id")
+        .and_then(|i| i.as_str())
+        .and_then(|i| Uuid::parse_str(i).ok())
+}
+
+// fetches every id we don't have yet in a single query
+fn fetch_missing(
+    c: &mut postgres::Client,
+    schema: &Schema,
+    ids: Vec<Uuid>,
+    fetched: &mut HashMap<Uuid, JSONValue>,
+) -> Result<(), CompassError> {
+    let mut missing: Vec<Uuid> = ids
+        .into_iter()
+        .filter(|id| !fetched.contains_key(id))
+        .collect();
+    missing.sort_unstable();
+    missing.dedup();
+
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    for event in get_by_ids(c, schema, &missing)? {
+        if let Some(id) = event_id(&event) {
+            fetched.insert(id, event);
+        }
+    }
+
+    Ok(())
+}
+
+fn with_children(
+    mut event: JSONValue,
+    fetched: &HashMap<Uuid, JSONValue>,
+    depth: usize,
+) -> JSONValue {
+    if depth > 0 {
+        if let Some(children) = metadata_of(&event).child_ids() {
+            event["metadata"]["children"] = json!(children
+                .into_iter()
+                .filter_map(|id| fetched.get(&id))
+                .map(|child| with_children(child.clone(), fetched, depth - 1))
+                .collect::<Vec<JSONValue>>());
+        }
+    }
+
+    event
+}
+
+// expands children, parents and siblings for a whole page of events with one query per level of depth
+fn expand_events(
+    c: &mut postgres::Client,
+    schema: &Schema,
+    events: &mut [JSONValue],
+    expansion: &Expansion,
+) -> Result<(), CompassError> {
+    if !(expansion.children || expansion.parent || expansion.siblings) {
+        return Ok(());
+    }
+
+    let mut fetched: HashMap<Uuid, JSONValue> = HashMap::new();
+
+    let mut ids = Vec::new();
+    for event in events.iter() {
+        let metadata = metadata_of(event);
+        if expansion.children {
+            ids.extend(metadata.child_ids().into_iter().flatten());
+        }
+        if expansion.parent {
+            ids.extend(metadata.parent);
+        }
+        if expansion.siblings {
+            ids.extend(metadata.sibling_event_ids().into_iter().flatten());
+        }
+    }
+    fetch_missing(c, schema, ids, &mut fetched)?;
+
+    if expansion.children {
+        let mut level: Vec<Uuid> = events
+            .iter()
+            .flat_map(|e| metadata_of(e).child_ids().unwrap_or_default())
+            .collect();
+        for _ in 1..expansion.depth {
+            level = level
+                .iter()
+                .filter_map(|id| fetched.get(id))
+                .flat_map(|e| metadata_of(e).child_ids().unwrap_or_default())
+                .collect();
+            if level.is_empty() {
+                break;
+            }
+            fetch_missing(c, schema, level.clone(), &mut fetched)?;
+        }
+    }
+
+    for event in events.iter_mut() {
+        let metadata = metadata_of(event);
+
+        if expansion.parent {
+            if let Some(parent) = metadata.parent {
+                event["metadata"]["parent"] = json!(fetched.get(&parent));
+            }
+        }
+
+        if expansion.siblings {
+            if let Some(siblings) = metadata.sibling_event_ids() {
+                event["metadata"]["_eventually_siblingEvents"] = json!(siblings
+                    .iter()
+                    .filter_map(|id| fetched.get(id))
+                    .collect::<Vec<&JSONValue>>());
+            }
+        }
+
+        if expansion.children {
+            *event = with_children(event.take(), &fetched, expansion.depth);
+        }
+    }
+
+    Ok(())
 }
 
 #[get("/one_of_each_type")]
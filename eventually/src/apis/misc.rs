@@ -2,41 +2,63 @@ use crate::*;
 use serde_json::json;
 use serde_json::Value as JSONValue;
 
-use lazy_static::lazy_static;
-use rocket::get;
-
-static TIME_MAP_BIN: &[u8] = include_bytes!("../../time_map.bin");
-
-lazy_static! {
-    static ref TIME_MAP: TimeMap = bincode::deserialize(TIME_MAP_BIN).unwrap();
-}
+use rocket::{get, post, State};
 
 async fn get_time(
     db: CompassConn,
     schema: Schema,
+    time_maps: &TimeMapStore,
     sim: String,
     season: i32,
     day: Option<i32>,
-) -> Result<JSONValue, EventuallyError> {
-    if sim == "thisidisstaticyo" && season <= 10 && season > -1 {
-        let season = TIME_MAP
-            .get(season as usize)
-            .ok_or(EventuallyError::TimeMapEntryNotFound)?;
-        if let Some(d) = day {
-            let day = season
-                .days
-                .get(d as usize)
-                .ok_or(EventuallyError::TimeMapEntryNotFound)?;
-            return Ok(json!({
-                "start": day.0,
-                "end": day.1
-            }));
-        } else {
-            return Ok(json!({
-                "start": season.lower_bound,
-                "end": season.higher_bound
-            }));
-        }
+) -> Result<(JSONValue, bool), EventuallyError> {
+    if let Some((start, end, settled)) =
+        time_maps.bounds(&sim, season as i64, day.map(|d| d as i64))
+    {
+        return Ok((
+            json!({
+                "start": start,
+                "end": end
+            }),
+            settled,
+        ));
+    }
+
+    // then time_bounds, which the monitor keeps up to date. a season only comes from there when
+    // it has every day up to its last, since otherwise the gaps could be the season's ends
+    let (b_sim, b_season, b_day) = (sim.clone(), season as i64, day.map(|d| d as i64));
+    let bounds = db
+        .run(move |c| {
+            c.query_one(
+                "SELECT min(first_created) AS first_created, max(last_created) AS last_created,
+                    count(*) AS days, min(day) AS first_day, max(day) AS last_day
+                FROM time_bounds WHERE sim = $1 AND season = $2 AND ($3::bigint IS NULL OR day = $3)",
+                &[&b_sim, &b_season, &b_day],
+            )
+        })
+        .await?;
+    let complete = day.is_some()
+        || (bounds.get::<&str, Option<i64>>("first_day") == Some(0)
+            && bounds.get::<&str, Option<i64>>("last_day")
+                == Some(bounds.get::<&str, i64>("days") - 1));
+    // times that are out of range can't be answered from here, so they fall through to the search
+    let created = |column: &str| {
+        bounds
+            .get::<&str, Option<i64>>(column)
+            .and_then(|t| Utc.timestamp_millis_opt(t).single())
+    };
+    if let (true, Some(first), Some(last)) =
+        (complete, created("first_created"), created("last_created"))
+    {
+        // the same format the search fallback below returns
+        let rfc3339 = |t: DateTime<Utc>| t.to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        return Ok((
+            json!({
+                "start": rfc3339(first),
+                "end": rfc3339(last)
+            }),
+            false,
+        ));
     }
 
     let mut query = HashMap::from([
@@ -63,29 +85,154 @@ async fn get_time(
         .pop()
         .and_then(|mut v| v.as_object_mut().and_then(|a| a.remove("created")));
 
-    Ok(json!({
-        "start": first_time,
-        "end": last_time
-    }))
+    Ok((
+        json!({
+            "start": first_time,
+            "end": last_time
+        }),
+        false,
+    ))
 }
 
 #[get("/time/<sim>/<season>")]
 pub async fn season_time_map(
     db: CompassConn,
     schema: Schema,
+    cache_config: &State<HttpCacheConfig>,
+    time_maps: &State<TimeMapStore>,
     sim: String,
     season: i32,
-) -> Result<JSONValue, EventuallyError> {
-    get_time(db, schema, sim, season, None).await
+) -> Result<Cached<JSONValue>, EventuallyError> {
+    let completed = cache_config.is_completed_season(&sim, season as i64);
+    let (time, immutable) = get_time(db, schema, time_maps, sim, season, None).await?;
+    Ok(cache_config.wrap(time, immutable || completed))
 }
 
 #[get("/time/<sim>/<season>/<day>")]
 pub async fn season_day_time_map(
     db: CompassConn,
     schema: Schema,
+    cache_config: &State<HttpCacheConfig>,
+    time_maps: &State<TimeMapStore>,
     sim: String,
     season: i32,
     day: i32,
+) -> Result<Cached<JSONValue>, EventuallyError> {
+    let completed = cache_config.is_completed_season(&sim, season as i64);
+    let (time, immutable) = get_time(db, schema, time_maps, sim, season, Some(day)).await?;
+    Ok(cache_config.wrap(time, immutable || completed))
+}
+
+// seasons per sim in the loaded time maps
+#[get("/admin/time_map")]
+pub async fn time_map_summary(_admin: AdminKey, time_maps: &State<TimeMapStore>) -> JSONValue {
+    json!(time_maps.summary())
+}
+
+#[post("/admin/time_map/refresh")]
+pub async fn refresh_time_map(
+    _admin: AdminKey,
+    db: CompassConn,
+    time_maps: &State<TimeMapStore>,
 ) -> Result<JSONValue, EventuallyError> {
-    get_time(db, schema, sim, season, Some(day)).await
+    time_maps.refresh(&db).await?;
+    Ok(json!(time_maps.summary()))
+}
+
+fn parse_timestamp(timestamp: &str) -> Result<DateTime<Utc>, EventuallyError> {
+    match timestamp.parse::<i64>() {
+        Ok(millis) => Utc
+            .timestamp_millis_opt(millis)
+            .single()
+            .ok_or_else(|| EventuallyError::InvalidTimestamp(timestamp.to_owned())),
+        Err(_) => DateTime::parse_from_rfc3339(timestamp)
+            .map(|t| t.with_timezone(&Utc))
+            .map_err(|_| EventuallyError::InvalidTimestamp(timestamp.to_owned())),
+    }
+}
+
+// the sim, season, day and phase a moment falls in, from the time maps where they cover it and
+// from the index otherwise. `betweenGames` is true once a day's last event has passed, so for the
+// day in progress it can be briefly wrong until the next event comes in
+#[get("/time/at?<timestamp>&<sim>")]
+pub async fn time_at(
+    db: CompassConn,
+    cache_config: &State<HttpCacheConfig>,
+    time_maps: &State<TimeMapStore>,
+    timestamp: String,
+    sim: Option<String>,
+) -> Result<Cached<JSONValue>, EventuallyError> {
+    let at = parse_timestamp(&timestamp)?;
+    let millis = at.timestamp_millis();
+    let mapped = time_maps.locate(sim.as_deref(), at);
+
+    // the latest event up to then, for the phase (and everything else when the time maps don't
+    // cover it)
+    let e_sim = mapped.as_ref().map(|m| m.sim.clone()).or(sim);
+    let latest = db
+        .run(move |c| {
+            c.query_opt(
+                "SELECT object->>'sim' AS sim, (object->>'season')::bigint AS season,
+                    (object->>'day')::bigint AS day, (object->>'phase')::bigint AS phase
+                FROM documents_millis
+                WHERE object #> '{created}' <= to_jsonb($1::bigint)
+                    AND ($2::text IS NULL OR object->>'sim' = $2)
+                ORDER BY object #> '{created}' DESC LIMIT 1",
+                &[&millis, &e_sim],
+            )
+        })
+        .await?;
+    let latest = latest.map(|r| {
+        (
+            r.get::<&str, Option<String>>("sim"),
+            r.get::<&str, Option<i64>>("season"),
+            r.get::<&str, Option<i64>>("day"),
+            r.get::<&str, Option<i64>>("phase"),
+        )
+    });
+
+    if let Some(position) = mapped {
+        let phase = latest
+            .filter(|l| l.1 == Some(position.season) && l.2 == Some(position.day))
+            .and_then(|l| l.3);
+        return Ok(cache_config.wrap(
+            json!({
+                "sim": position.sim,
+                "season": position.season,
+                "day": position.day,
+                "phase": phase,
+                "betweenGames": position.between_games
+            }),
+            position.settled,
+        ));
+    }
+
+    let (sim, season, day, phase) = match latest {
+        Some((Some(sim), Some(season), Some(day), phase)) => (sim, season, day, phase),
+        _ => return Err(EventuallyError::TimeMapEntryNotFound),
+    };
+
+    let (b_sim, b_season, b_day) = (sim.clone(), season, day);
+    let last_created = db
+        .run(move |c| {
+            c.query_opt(
+                "SELECT last_created FROM time_bounds WHERE sim = $1 AND season = $2 AND day = $3",
+                &[&b_sim, &b_season, &b_day],
+            )
+        })
+        .await?
+        .map(|r| r.get::<&str, i64>("last_created"));
+
+    let completed = cache_config.is_completed_season(&sim, season);
+    Ok(cache_config.wrap(
+        json!({
+            "sim": sim,
+            "season": season,
+            "day": day,
+            "phase": phase,
+            // unknown until time_bounds has the day
+            "betweenGames": last_created.map(|l| millis > l)
+        }),
+        completed,
+    ))
 }
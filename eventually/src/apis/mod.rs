@@ -1,3 +1,4 @@
 pub mod eventually;
 pub mod misc;
 pub mod sachet;
+pub mod sachet_admin;
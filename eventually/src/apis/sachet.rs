@@ -1,29 +1,74 @@
+use serde::de::IgnoredAny;
 use serde::{Deserialize, Serialize};
-use serde_json::Value as JSONValue;
+use serde_json::{json, Value as JSONValue};
 use sled::Db as SledDB;
 
-use rocket::serde::{json::Json as RocketJson, uuid::Uuid};
-use rocket::{get, State};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
 
+use rocket::http::ContentType;
+use rocket::response::stream::{Event, EventStream, TextStream};
+use rocket::serde::uuid::Uuid;
+use rocket::tokio::time::sleep;
+use rocket::{get, FromForm, State};
+
+use futures_util::stream::{self, BoxStream};
 use futures_util::{pin_mut, StreamExt};
 
 use crab::chron::{self, v1};
+use log::{debug, error, warn};
 
 use crate::*;
 
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SachetConfig {
+    // fetch game updates from chronicler for games that aren't in the game_updates table
+    pub chronicler_fallback: bool,
+    pub chronicler_url: String,
+    // games to pre-generate packets for once the server is up
+    pub warm_on_launch: Option<WarmRange>,
+}
+
+// games to pre-generate packets for, from the admin api or warm_on_launch
+#[derive(Deserialize, FromForm, Debug, Clone)]
+pub struct WarmRange {
+    pub sim: Option<String>,
+    pub season_min: i64,
+    pub season_max: i64,
+    pub day_min: Option<i64>,
+    pub day_max: Option<i64>,
+    // how many games are generated at once, each holding a database connection. capped at half
+    // the connection pool
+    pub concurrency: Option<usize>,
+}
+
+// each season in a range is its own query, so ranges can't be arbitrarily wide
+pub const MAX_WARM_SEASONS: i64 = 100;
+
+impl Default for SachetConfig {
+    fn default() -> Self {
+        SachetConfig {
+            chronicler_fallback: true,
+            chronicler_url: "https://api.sibr.dev/chronicler/v1/games/updates".to_owned(),
+            warm_on_launch: None,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct ChronGameUpdate {
     #[serde(skip_serializing)]
-    timestamp: DateTime<Utc>,
-    data: GameUpdate,
+    pub timestamp: DateTime<Utc>,
+    pub data: GameUpdate,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 #[serde(rename_all = "camelCase", default)]
 pub struct GameUpdate {
-    #[serde(rename(serialize = "gameId"))]
-    id: String,
+    #[serde(rename(serialize = "gameId"), alias = "gameId")]
+    pub id: String,
     home_team: Option<String>,
     away_team: Option<String>,
     home_batter: Option<String>,
@@ -72,31 +117,12 @@ pub struct GameUpdate {
     secret_baserunner: Option<String>,
     state: Option<JSONValue>,
     #[serde(skip_serializing)]
-    play_count: i64,
+    pub play_count: i64,
     #[serde(skip_serializing)]
-    game_complete: Option<bool>,
+    pub game_complete: Option<bool>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-#[serde(rename_all = "camelCase")]
-pub struct FeedEvent {
-    id: String,
-    player_tags: Option<Vec<String>>,
-    team_tags: Option<Vec<String>>,
-    game_tags: Option<Vec<String>>,
-    metadata: Option<JSONValue>,
-    created: DateTime<Utc>,
-    day: i64,
-    season: i64,
-    phase: i64,
-    tournament: i64,
-    r#type: i64,
-    category: i64,
-    description: String,
-    nuts: Option<i64>,
-}
-
-#[derive(Deserialize, Serialize)]
+#[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Packet {
     play_count: i64,
@@ -109,70 +135,380 @@ pub struct Packet {
     game_update: Option<GameUpdate>,
 }
 
-#[derive(Serialize)]
-#[serde(rename_all = "camelCase")]
+// packets as they're kept in the sled cache. Packet flattens its parts together for the api,
+// which doesn't deserialize back reliably, so they're kept apart here
+#[derive(Deserialize, Serialize)]
+struct StoredPacket {
+    play_count: i64,
+    sub_play: i64,
+    incomplete: bool,
+    feed: FeedEvent,
+    game_update: Option<GameUpdate>,
+}
+
+impl From<&Packet> for StoredPacket {
+    fn from(p: &Packet) -> StoredPacket {
+        StoredPacket {
+            play_count: p.play_count,
+            sub_play: p.sub_play,
+            incomplete: p._packet_incomplete,
+            feed: p.feed.clone(),
+            game_update: p.game_update.clone(),
+        }
+    }
+}
+
+impl From<StoredPacket> for Packet {
+    fn from(p: StoredPacket) -> Packet {
+        Packet {
+            play_count: p.play_count,
+            sub_play: p.sub_play,
+            _packet_incomplete: p.incomplete,
+            feed: p.feed,
+            game_update: p.game_update,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct CacheEntry {
+    // game generation the packets were generated at, see game_generations
+    pub generation: i64,
+    pub cached_at: DateTime<Utc>,
+    packets: Vec<StoredPacket>,
+}
+
+impl CacheEntry {
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    pub fn into_packets(self) -> Vec<Packet> {
+        self.packets.into_iter().map(Packet::from).collect()
+    }
+}
+
+// the parts of a CacheEntry that describe it, read without building any of its packets
+#[derive(Deserialize)]
+pub struct CacheEntryHeader {
+    pub generation: i64,
+    pub cached_at: DateTime<Utc>,
+    packets: Vec<IgnoredAny>,
+}
+
+impl CacheEntryHeader {
+    pub fn packet_count(&self) -> usize {
+        self.packets.len()
+    }
+}
+
+pub fn read_cache_entry(cache: &SledDB, id: &Uuid) -> Result<Option<CacheEntry>, EventuallyError> {
+    match cache.get(id.as_bytes())? {
+        Some(bytes) => match serde_json::from_slice(&bytes) {
+            Ok(entry) => Ok(Some(entry)),
+            Err(e) => {
+                // most likely written by an older version, so just treat it as missing
+                warn!("discarding unreadable cache entry for {}: {}", id, e);
+                Ok(None)
+            }
+        },
+        None => Ok(None),
+    }
+}
+
+pub fn game_generation(c: &mut postgres::Client, id: &Uuid) -> Result<i64, EventuallyError> {
+    Ok(c.query_opt(
+        "SELECT generation FROM game_generations WHERE game_id = $1",
+        &[id],
+    )?
+    .map(|row| row.get("generation"))
+    .unwrap_or(0))
+}
+
+// marks every cached copy of a game's packets as stale
+pub fn bump_game_generation<C: postgres::GenericClient>(
+    c: &mut C,
+    id: &Uuid,
+) -> Result<(), postgres::Error> {
+    c.execute(
+        "INSERT INTO game_generations (game_id, generation) VALUES ($1, 1) ON CONFLICT (game_id) DO UPDATE SET generation = game_generations.generation + 1",
+        &[id],
+    )?;
+    Ok(())
+}
+
+// ids of every game tagged in events within the given seasons (and days, if any). seasons are
+// looked up one at a time by containment, which the GIN index on documents_millis covers
+pub fn find_game_ids(
+    c: &mut postgres::Client,
+    sim: Option<String>,
+    seasons: (i64, i64),
+    days: Option<(i64, i64)>,
+) -> Result<Vec<Uuid>, postgres::Error> {
+    let (day_min, day_max) = days.unwrap_or((i64::MIN, i64::MAX));
+    let mut games = BTreeSet::new();
+    for season in seasons.0..=seasons.1 {
+        let mut filter = json!({ "season": season });
+        if let Some(sim) = &sim {
+            filter["sim"] = json!(sim);
+        }
+        if day_min == day_max {
+            filter["day"] = json!(day_min);
+        }
+
+        let rows = c.query(
+            "SELECT DISTINCT jsonb_array_elements_text(object->'gameTags') AS game FROM documents_millis
+                WHERE object @> $1
+                AND jsonb_typeof(object->'gameTags') = 'array'
+                AND jsonb_typeof(object->'day') = 'number'
+                AND (object->>'day')::bigint BETWEEN $2 AND $3",
+            &[&filter, &day_min, &day_max],
+        )?;
+        games.extend(
+            rows.iter()
+                .filter_map(|row| Uuid::parse_str(row.get("game")).ok()),
+        );
+    }
+    Ok(games.into_iter().collect())
+}
+
+// every feed event sharing a play and sub-play
 struct Pallet {
     play_count: i64,
     sub_play: i64,
-    #[serde(flatten)]
     feed: Vec<FeedEvent>,
-    #[serde(flatten)]
-    game_update: Option<ChronGameUpdate>,
 }
 
-#[get("/packets?<id>")]
+fn in_play_range(
+    packets: Vec<Packet>,
+    from_play: Option<i64>,
+    to_play: Option<i64>,
+) -> Vec<Packet> {
+    packets
+        .into_iter()
+        .filter(|p| from_play.map(|from| p.play_count >= from).unwrap_or(true))
+        .filter(|p| to_play.map(|to| p.play_count <= to).unwrap_or(true))
+        .collect()
+}
+
+#[get("/packets?<id>&<from_play>&<to_play>")]
 pub async fn get_packets(
     db: CompassConn,
     cache: &State<SledDB>,
     id: Uuid,
-    schema: Schema,
-) -> Result<RocketJson<Vec<Packet>>, EventuallyError> {
-    if let Some(packet_bytes) = cache.get(&id.as_bytes())? {
-        let packets = serde_json::from_slice(&packet_bytes)?;
-        Ok(RocketJson(packets))
-    } else {
-        gen_packets(db, cache, id, schema).await.map(RocketJson)
+    from_play: Option<i64>,
+    to_play: Option<i64>,
+    config: &State<SachetConfig>,
+    cache_config: &State<HttpCacheConfig>,
+) -> Result<Cached<Vec<Packet>>, EventuallyError> {
+    let (packets, _) = load_packets(&db, cache, id, config).await?;
+    // even finished games change when updates come in late or the cache is purged, so clients
+    // revalidate against the etag rather than keeping these forever
+    Ok(cache_config.wrap(in_play_range(packets, from_play, to_play), false))
+}
+
+// how many of a day's games are loaded at once
+const DAY_CONCURRENCY: usize = 4;
+
+// packets for every game played on a day, keyed by game id
+#[get("/day?<sim>&<season>&<day>")]
+pub async fn get_day(
+    db: CompassConn,
+    cache: &State<SledDB>,
+    sim: Option<String>,
+    season: i64,
+    day: i64,
+    config: &State<SachetConfig>,
+    cache_config: &State<HttpCacheConfig>,
+) -> Result<Cached<BTreeMap<String, Vec<Packet>>>, EventuallyError> {
+    let games = db
+        .run(move |c| find_game_ids(c, sim, (season, season), Some((day, day))))
+        .await?;
+
+    // games that have to be generated wait on the database one at a time, but cached games and
+    // chronicler fetches don't hold each other up
+    let db = &db;
+    let results = stream::iter(games)
+        .map(|id| async move {
+            load_packets(db, cache, id, config)
+                .await
+                .map(|(game_packets, _)| (id, game_packets))
+        })
+        .buffer_unordered(DAY_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut packets = BTreeMap::new();
+    for result in results {
+        let (id, game_packets) = result?;
+        packets.insert(format!("{}", id.to_hyphenated_ref()), game_packets);
     }
+
+    // revalidated like single games, see get_packets
+    Ok(cache_config.wrap(packets, false))
 }
 
-pub async fn gen_packets(
+// longest we'll wait between two packets when pacing, so gaps in a game don't stall the stream
+const MAX_PACE_GAP: Duration = Duration::from_secs(60);
+// speeds outside this are clamped to it
+const MIN_SPEED: f64 = 0.01;
+const MAX_SPEED: f64 = 1000.0;
+
+// emits packets in order, optionally spaced out like they originally happened, sped up by `speed`
+fn paced(packets: Vec<Packet>, speed: Option<f64>) -> BoxStream<'static, Packet> {
+    let speed = speed.map(|s| s.max(MIN_SPEED).min(MAX_SPEED));
+    stream::unfold(
+        (packets.into_iter(), None),
+        move |(mut packets, last): (std::vec::IntoIter<Packet>, Option<DateTime<Utc>>)| async move {
+            let packet = packets.next()?;
+            let created = packet.feed.created();
+            if let (Some(speed), Some(last), Some(created)) = (speed, last, created) {
+                let gap = (created - last).to_std().unwrap_or_default();
+                // capped before it's turned back into a Duration, so it's always in range
+                let secs = (gap.as_secs_f64() / speed).min(MAX_PACE_GAP.as_secs_f64());
+                sleep(Duration::from_secs_f64(secs)).await;
+            }
+
+            Some((packet, (packets, created.or(last))))
+        },
+    )
+    .boxed()
+}
+
+pub enum PacketStream {
+    Sse(EventStream<BoxStream<'static, Event>>),
+    NdJson(TextStream<BoxStream<'static, String>>),
+}
+
+impl<'r> Responder<'r, 'r> for PacketStream {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'r> {
+        match self {
+            PacketStream::Sse(s) => s.respond_to(req),
+            PacketStream::NdJson(s) => {
+                let mut response = s.respond_to(req)?;
+                response.set_header(ContentType::new("application", "x-ndjson"));
+                Ok(response)
+            }
+        }
+    }
+}
+
+// replays a game's packets as server-sent events (the default) or newline-delimited json.
+// with `pace`, packets are spaced out like they originally happened, `speed` times faster
+#[allow(clippy::too_many_arguments)]
+#[get("/packets/stream?<id>&<from_play>&<to_play>&<format>&<pace>&<speed>")]
+pub async fn stream_packets(
     db: CompassConn,
+    cache: &State<SledDB>,
+    id: Uuid,
+    from_play: Option<i64>,
+    to_play: Option<i64>,
+    format: Option<String>,
+    pace: Option<bool>,
+    speed: Option<f64>,
+    config: &State<SachetConfig>,
+) -> Result<PacketStream, EventuallyError> {
+    if let Some(s) = speed.filter(|s| !s.is_finite()) {
+        return Err(EventuallyError::InvalidSpeed(s));
+    }
+    let (packets, _) = load_packets(&db, cache, id, config).await?;
+    let speed = if pace.unwrap_or(false) {
+        Some(speed.unwrap_or(1.0))
+    } else {
+        None
+    };
+    let packets = paced(in_play_range(packets, from_play, to_play), speed);
+
+    Ok(match format.as_deref() {
+        Some("ndjson") => PacketStream::NdJson(TextStream(
+            packets
+                .filter_map(|p| async move { serde_json::to_string(&p).ok().map(|s| s + "\n") })
+                .boxed(),
+        )),
+        _ => PacketStream::Sse(EventStream::from(packets.map(|p| Event::json(&p)).boxed())),
+    })
+}
+
+// returns the packets for a game, and whether they came out of the cache
+pub async fn load_packets(
+    db: &CompassConn,
     cache: &SledDB,
     id: Uuid,
-    schema: Schema,
-) -> Result<Vec<Packet>, EventuallyError> {
-    let mut pallets: HashMap<i64, Pallet> = HashMap::new();
-    let game = format!("{}", id.to_hyphenated_ref());
+    config: &SachetConfig,
+) -> Result<(Vec<Packet>, bool), EventuallyError> {
+    if let Some(entry) = read_cache_entry(cache, &id)? {
+        let current = db.run(move |c| game_generation(c, &id)).await?;
+        if entry.generation >= current {
+            return Ok((entry.into_packets(), true));
+        }
+        debug!(
+            "cached packets for {} are stale (generation {} < {})",
+            id, entry.generation, current
+        );
+    }
 
-    for event in db
-        .run(move |c| {
-            json_search(
-                c,
-                &schema,
-                &(vec![
-                    ("gameTags".to_owned(), game),
-                    ("limit".to_owned(), "10000000".to_owned()),
-                ]
-                .into_iter()
-                .collect::<HashMap<String, String>>()),
-                None,
-            )
+    gen_packets(db, cache, id, config)
+        .await
+        .map(|packets| (packets, false))
+}
+
+impl ChronGameUpdate {
+    fn from_row(row: &postgres::Row) -> Result<ChronGameUpdate, serde_json::Error> {
+        let mut data: GameUpdate = serde_json::from_value(row.get("data"))?;
+        data.play_count = row.get("play_count");
+        data.game_complete = Some(row.get("game_complete"));
+        Ok(ChronGameUpdate {
+            timestamp: Utc.timestamp_millis(row.get("observed")),
+            data,
         })
-        .await?
-    {
-        let packet = pallets
-            .entry(event["metadata"]["play"].as_i64().unwrap())
-            .or_insert(Pallet {
-                play_count: event["metadata"]["play"].as_i64().unwrap(),
-                sub_play: event["metadata"]["subPlay"].as_i64().unwrap(),
-                feed: vec![],
-                game_update: None,
-            });
-        packet
-            .feed
-            .push(serde_json::from_value::<FeedEvent>(event)?);
+    }
+}
+
+pub fn stored_game_updates(
+    c: &mut postgres::Client,
+    id: &Uuid,
+) -> Result<Vec<ChronGameUpdate>, EventuallyError> {
+    c.query(
+        "SELECT play_count, observed, game_complete, data FROM game_updates WHERE game_id = $1 ORDER BY play_count",
+        &[id],
+    )?
+    .iter()
+    .map(|row| ChronGameUpdate::from_row(row).map_err(EventuallyError::from))
+    .collect()
+}
+
+// upserts a game update, keeping whichever update for that play was observed last.
+// returns whether anything was written
+pub fn store_game_update<C: postgres::GenericClient>(
+    c: &mut C,
+    update: &ChronGameUpdate,
+) -> Result<bool, EventuallyError> {
+    let game_id = Uuid::parse_str(&update.data.id).map_err(|_| EventuallyError::InvalidGameId)?;
+    let written = c.execute(
+        "INSERT INTO game_updates (game_id, play_count, observed, game_complete, data) VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (game_id, play_count) DO UPDATE SET observed = EXCLUDED.observed, game_complete = EXCLUDED.game_complete, data = EXCLUDED.data
+            WHERE game_updates.observed < EXCLUDED.observed",
+        &[
+            &game_id,
+            &update.data.play_count,
+            &update.timestamp.timestamp_millis(),
+            &update.data.game_complete.unwrap_or(false),
+            &serde_json::to_value(&update.data)?,
+        ],
+    )?;
+
+    if written > 0 {
+        bump_game_generation(c, &game_id)?;
     }
 
+    Ok(written > 0)
+}
+
+async fn fetch_chronicler_updates(url: &str, id: Uuid) -> Vec<ChronGameUpdate> {
     let client = reqwest::Client::default();
 
     let req: v1::GameUpdatesRequest = v1::GameUpdatesRequestBuilder::default()
@@ -180,81 +516,125 @@ pub async fn gen_packets(
         .count(1000usize)
         .build()
         .unwrap();
-    let s = chron::v1::fetch::<ChronGameUpdate, v1::GameUpdatesRequest>(
-        &client,
-        "https://api.sibr.dev/chronicler/v1/games/updates",
-        req,
-    );
+    let s = chron::v1::fetch::<ChronGameUpdate, v1::GameUpdatesRequest>(&client, url, req);
 
     pin_mut!(s);
 
-    let mut game_over = false;
-    let mut last_time = Utc::now();
-
+    let mut updates = Vec::new();
     while let Some(val) = s.next().await {
-        game_over = val
-            .as_ref()
-            .ok()
-            .and_then(|v| v.data.game_complete)
-            .unwrap_or(false);
-
-        if let Ok(t) = val.as_ref().map(|v| v.timestamp) {
-            last_time = t;
+        match val {
+            Ok(update) => updates.push(update),
+            Err(e) => error!(
+                "couldn't fetch game updates for {} from chronicler: {}",
+                id, e
+            ),
         }
+    }
 
-        if let Some(count) = val.as_ref().ok().map(|v| v.data.play_count) {
-            let packet = pallets.entry(count - 1).or_insert(Pallet {
-                play_count: count - 1,
-                sub_play: -1,
-                feed: vec![],
-                game_update: None,
-            });
-
-            let timestamp: DateTime<Utc> = val.as_ref().unwrap().timestamp;
-            if timestamp
-                > packet
-                    .game_update
-                    .as_ref()
-                    .map(|v| v.timestamp)
-                    .unwrap_or(Utc.timestamp(0, 0))
-            {
-                packet.game_update = val.ok();
+    updates
+}
+
+pub async fn gen_packets(
+    db: &CompassConn,
+    cache: &SledDB,
+    id: Uuid,
+    config: &SachetConfig,
+) -> Result<Vec<Packet>, EventuallyError> {
+    // read before anything else, so changes landing while we generate leave the entry stale
+    let generation = db.run(move |c| game_generation(c, &id)).await?;
+
+    let mut pallets: HashMap<(i64, i64), Pallet> = HashMap::new();
+    // every event tagged with the game, by containment so the GIN index covers it
+    let filter = json!({ "gameTags": [id.to_hyphenated_ref().to_string()] });
+
+    for row in db
+        .run(move |c| {
+            c.query(
+                "SELECT object FROM documents_millis WHERE object @> $1",
+                &[&filter],
+            )
+        })
+        .await?
+    {
+        let event = serde_json::from_value::<FeedEvent>(row.get("object"))?.with_feed_created();
+        let metadata = event.metadata();
+        let play = match metadata.play {
+            Some(p) => p,
+            None => {
+                warn!(
+                    "skipping event {} in game {} without a play number",
+                    event.id, id
+                );
+                continue;
             }
+        };
+        let sub_play = metadata.sub_play.unwrap_or(-1);
+
+        let pallet = pallets.entry((play, sub_play)).or_insert(Pallet {
+            play_count: play,
+            sub_play,
+            feed: vec![],
+        });
+        pallet.feed.push(event);
+    }
+
+    let stored = db.run(move |c| stored_game_updates(c, &id)).await?;
+    let updates = if stored.is_empty() && config.chronicler_fallback {
+        fetch_chronicler_updates(&config.chronicler_url, id).await
+    } else {
+        stored
+    };
+
+    let game_over = updates
+        .iter()
+        .any(|u| u.data.game_complete.unwrap_or(false));
+    let last_time = updates
+        .iter()
+        .map(|u| u.timestamp)
+        .max()
+        .unwrap_or_else(Utc::now);
+
+    // the latest update for each play, keyed by the play it describes
+    let mut game_updates: HashMap<i64, ChronGameUpdate> = HashMap::new();
+    for update in updates {
+        let play = update.data.play_count - 1;
+        let newer = game_updates
+            .get(&play)
+            .map(|existing| update.timestamp > existing.timestamp)
+            .unwrap_or(true);
+        if newer {
+            game_updates.insert(play, update);
         }
     }
 
     let mut packets: Vec<Packet> = pallets
-        .into_iter()
-        .map(|(_, v)| {
-            let mut packets = Vec::new();
-            for ev in v.feed {
-                packets.push(if let Some(g_update) = v.game_update.as_ref() {
-                    Packet {
-                        play_count: v.play_count,
-                        sub_play: v.sub_play,
-                        _packet_incomplete: false,
-                        feed: ev,
-                        game_update: Some(g_update.data.clone()),
-                    }
-                } else {
-                    Packet {
-                        play_count: v.play_count,
-                        sub_play: v.sub_play,
-                        _packet_incomplete: true,
-                        feed: ev,
-                        game_update: None,
-                    }
-                });
-            }
-            packets
+        .into_values()
+        .flat_map(|pallet| {
+            let Pallet {
+                play_count,
+                sub_play,
+                feed,
+            } = pallet;
+            let game_update = game_updates.get(&play_count).map(|u| u.data.clone());
+            feed.into_iter().map(move |ev| Packet {
+                play_count,
+                sub_play,
+                _packet_incomplete: game_update.is_none(),
+                feed: ev,
+                game_update: game_update.clone(),
+            })
         })
-        .flatten()
         .collect();
 
-    packets.sort_by_key(|v| v.play_count);
+    packets.sort_by_key(|v| (v.play_count, v.sub_play, v.feed.created()));
 
     if game_over && (Utc::now() - last_time).num_seconds() > 120 {
-        cache.insert(id.as_bytes(), serde_json::to_vec(&packets)?)?;
+        let entry = CacheEntry {
+            generation,
+            cached_at: Utc::now(),
+            packets: packets.iter().map(StoredPacket::from).collect(),
+        };
+        cache.insert(id.as_bytes(), serde_json::to_vec(&entry)?)?;
     }
 
     Ok(packets)
@@ -0,0 +1,282 @@
+use log::{error, info};
+use serde_json::json;
+use serde_json::Value as JSONValue;
+use sled::Db as SledDB;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use rocket::serde::uuid::Uuid;
+use rocket::tokio::task;
+use rocket::{delete, get, post, Orbit, Rocket, State};
+
+use futures_util::future::join_all;
+
+use crate::sachet::*;
+use crate::*;
+
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct WarmProgress {
+    pub running: bool,
+    pub total: usize,
+    pub done: usize,
+    pub cached: usize,
+    pub failed: usize,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+// tracks the background task pre-generating packets into the cache
+#[derive(Clone, Default)]
+pub struct Warmer(Arc<Mutex<WarmProgress>>);
+
+impl Warmer {
+    pub fn progress(&self) -> WarmProgress {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn record(&self, result: Result<bool, EventuallyError>, id: &Uuid) {
+        let mut progress = self.0.lock().unwrap();
+        progress.done += 1;
+        match result {
+            Ok(true) => progress.cached += 1,
+            Ok(false) => {}
+            Err(e) => {
+                error!("couldn't warm packets for {}: {}", id, e);
+                progress.failed += 1;
+            }
+        }
+
+        if progress.done % 100 == 0 || progress.done == progress.total {
+            info!(
+                "warmed {}/{} games ({} cached, {} failed)",
+                progress.done, progress.total, progress.cached, progress.failed
+            );
+        }
+    }
+}
+
+async fn warm_worker(
+    db: CompassConn,
+    queue: Arc<Mutex<VecDeque<Uuid>>>,
+    cache: SledDB,
+    config: SachetConfig,
+    warmer: Warmer,
+) {
+    loop {
+        let id = match queue.lock().unwrap().pop_front() {
+            Some(id) => id,
+            None => break,
+        };
+
+        // load_packets skips games that are already cached and fresh
+        let result = match load_packets(&db, &cache, id, &config).await {
+            Ok((_, true)) => Ok(true),
+            Ok((_, false)) => cache
+                .contains_key(id.as_bytes())
+                .map_err(EventuallyError::from),
+            Err(e) => Err(e),
+        };
+        warmer.record(result, &id);
+    }
+}
+
+// finds every game in the range and pre-generates its packets in the background,
+// returning how many games were queued
+pub async fn start_warming(
+    rocket: &Rocket<Orbit>,
+    range: WarmRange,
+) -> Result<usize, EventuallyError> {
+    if range.season_max < range.season_min
+        || range.season_max - range.season_min >= MAX_WARM_SEASONS
+    {
+        return Err(EventuallyError::InvalidRange(format!(
+            "seasons {} to {}, expected at most {} seasons",
+            range.season_min, range.season_max, MAX_WARM_SEASONS
+        )));
+    }
+
+    let warmer = rocket.state::<Warmer>().cloned().unwrap_or_default();
+    let cache = rocket
+        .state::<SledDB>()
+        .cloned()
+        .ok_or(EventuallyError::MissingState("sled cache"))?;
+    let config = rocket.state::<SachetConfig>().cloned().unwrap_or_default();
+
+    {
+        let mut progress = warmer.0.lock().unwrap();
+        if progress.running {
+            return Err(EventuallyError::WarmerBusy);
+        }
+        progress.running = true;
+    }
+
+    // each worker holds a connection for as long as it runs, so leave half the pool to requests
+    let pool_size = rocket_sync_db_pools::Config::from("eventually", rocket)
+        .map(|c| c.pool_size as usize)
+        .unwrap_or(1);
+    let concurrency = range.concurrency.unwrap_or(4).min(pool_size / 2).max(1);
+
+    let conn = match CompassConn::get_one(rocket).await {
+        Some(c) => c,
+        None => {
+            warmer.0.lock().unwrap().running = false;
+            return Err(EventuallyError::NoDatabase);
+        }
+    };
+
+    let (sim, seasons) = (range.sim.clone(), (range.season_min, range.season_max));
+    let days = if range.day_min.is_some() || range.day_max.is_some() {
+        Some((
+            range.day_min.unwrap_or(i64::MIN),
+            range.day_max.unwrap_or(i64::MAX),
+        ))
+    } else {
+        None
+    };
+    let ids = match conn
+        .run(move |c| find_game_ids(c, sim, seasons, days))
+        .await
+    {
+        Ok(ids) => ids,
+        Err(e) => {
+            warmer.0.lock().unwrap().running = false;
+            return Err(e.into());
+        }
+    };
+
+    let mut conns = vec![conn];
+    for _ in 1..concurrency {
+        match CompassConn::get_one(rocket).await {
+            Some(c) => conns.push(c),
+            None => break,
+        }
+    }
+
+    let total = ids.len();
+    *warmer.0.lock().unwrap() = WarmProgress {
+        running: true,
+        total,
+        started_at: Some(Utc::now()),
+        ..WarmProgress::default()
+    };
+    info!(
+        "warming packets for {} games with {} connections",
+        total,
+        conns.len()
+    );
+
+    let queue = Arc::new(Mutex::new(ids.into_iter().collect::<VecDeque<Uuid>>()));
+    rocket::tokio::spawn(async move {
+        join_all(conns.into_iter().map(|conn| {
+            warm_worker(
+                conn,
+                queue.clone(),
+                cache.clone(),
+                config.clone(),
+                warmer.clone(),
+            )
+        }))
+        .await;
+
+        let mut progress = warmer.0.lock().unwrap();
+        progress.running = false;
+        progress.finished_at = Some(Utc::now());
+        info!(
+            "finished warming packets: {} cached, {} failed",
+            progress.cached, progress.failed
+        );
+    });
+
+    Ok(total)
+}
+
+#[post("/admin/warm?<range..>")]
+pub async fn warm_range(
+    _admin: AdminKey,
+    rocket: &Rocket<Orbit>,
+    range: WarmRange,
+) -> Result<JSONValue, EventuallyError> {
+    let queued = start_warming(rocket, range).await?;
+    Ok(json!({ "queued": queued }))
+}
+
+#[get("/admin/warm")]
+pub async fn warm_progress(_admin: AdminKey, warmer: &State<Warmer>) -> JSONValue {
+    json!(warmer.progress())
+}
+
+#[get("/admin/cache")]
+pub async fn list_cache(
+    _admin: AdminKey,
+    cache: &State<SledDB>,
+) -> Result<JSONValue, EventuallyError> {
+    // walking the whole cache is slow enough to hold up other requests on the executor
+    let cache = SledDB::clone(cache);
+    task::spawn_blocking(move || -> Result<JSONValue, EventuallyError> {
+        let mut games = Vec::new();
+        for item in cache.iter() {
+            let (key, value) = item?;
+            let id = match Uuid::from_slice(&key) {
+                Ok(id) => id,
+                Err(_) => continue,
+            };
+
+            games.push(match serde_json::from_slice::<CacheEntryHeader>(&value) {
+                Ok(entry) => json!({
+                    "id": id,
+                    "generation": entry.generation,
+                    "cachedAt": entry.cached_at,
+                    "packets": entry.packet_count(),
+                    "bytes": value.len(),
+                }),
+                Err(_) => json!({
+                    "id": id,
+                    "unreadable": true,
+                    "bytes": value.len(),
+                }),
+            });
+        }
+
+        Ok(json!(games))
+    })
+    .await?
+}
+
+#[delete("/admin/cache")]
+pub async fn purge_cache(
+    _admin: AdminKey,
+    cache: &State<SledDB>,
+) -> Result<JSONValue, EventuallyError> {
+    let purged = cache.len();
+    cache.clear()?;
+    Ok(json!({ "purged": purged }))
+}
+
+#[delete("/admin/cache/<id>")]
+pub async fn purge_game(
+    _admin: AdminKey,
+    cache: &State<SledDB>,
+    id: Uuid,
+) -> Result<JSONValue, EventuallyError> {
+    let purged = cache.remove(id.as_bytes())?.is_some();
+    Ok(json!({ "id": id, "purged": purged }))
+}
+
+#[post("/admin/cache/<id>/warm")]
+pub async fn warm_game(
+    _admin: AdminKey,
+    db: CompassConn,
+    cache: &State<SledDB>,
+    id: Uuid,
+    config: &State<SachetConfig>,
+) -> Result<JSONValue, EventuallyError> {
+    cache.remove(id.as_bytes())?;
+    let packets = gen_packets(&db, cache, id, config).await?;
+    let cached = cache.contains_key(id.as_bytes())?;
+    Ok(json!({
+        "id": id,
+        "packets": packets.len(),
+        "cached": cached,
+    }))
+}
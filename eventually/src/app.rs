@@ -0,0 +1,162 @@
+use crate::*;
+use log::error;
+use sled::Db as SledDB;
+use std::fs::File;
+use std::io::Read;
+use std::time::Duration;
+
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
+use rocket::{routes, Build, Rocket};
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct EventuallyConfig {
+    cache_zstd: bool,
+    cache_temporary: bool,
+    cache_path: Option<String>,
+    cache_mem_size: Option<u64>,
+}
+
+impl Default for EventuallyConfig {
+    fn default() -> Self {
+        EventuallyConfig {
+            cache_zstd: true,
+            cache_temporary: true,
+            cache_path: None,
+            cache_mem_size: None,
+        }
+    }
+}
+
+impl EventuallyConfig {
+    pub fn into_db(&self) -> sled::Result<SledDB> {
+        let mut config = sled::Config::default()
+            .use_compression(self.cache_zstd)
+            .temporary(self.cache_temporary);
+        if let Some(ref p) = self.cache_path {
+            config = config.path(p);
+        }
+
+        if let Some(size) = self.cache_mem_size {
+            config = config.cache_capacity(size);
+        }
+
+        config.open()
+    }
+}
+
+// the whole server, configured from `figment`. the server binary launches this with rocket's
+// default figment, and the tests mount it with their own
+pub fn build_rocket(figment: Figment) -> Rocket<Build> {
+    let rocket = rocket::custom(figment);
+    let figment = rocket.figment();
+
+    let config: EventuallyConfig = figment.extract().unwrap_or_default();
+    let db = config.into_db().expect("couldn't open sled cache");
+
+    let rate_limit: RateLimitConfig = figment.extract_inner("rate_limit").unwrap_or_default();
+    let api_keys = ApiKeyStore::new(
+        Duration::from_secs(rate_limit.key_cache_ttl),
+        rate_limit.key_cache_size,
+    );
+    // leaving out the cors section means the defaults, but a broken one shouldn't quietly
+    // become them
+    let cors: CorsConfig = match figment.find_value("cors") {
+        Ok(_) => figment.extract_inner("cors").expect("invalid cors config"),
+        Err(_) => CorsConfig::default(),
+    };
+    if let Err(e) = cors.validate() {
+        panic!("invalid cors config: {}", e);
+    }
+    let http_cache: HttpCacheConfig = figment.extract_inner("http_cache").unwrap_or_default();
+    let compression: CompressionConfig = figment.extract_inner("compression").unwrap_or_default();
+    let sachet_config: sachet::SachetConfig = figment.extract_inner("sachet").unwrap_or_default();
+    let mut time_map: TimeMapConfig = figment.extract_inner("time_map").unwrap_or_default();
+    // so the server finds the files wherever it's started from
+    if let Some(dir) = figment
+        .find_metadata("time_map.files")
+        .and_then(|m| m.source.as_ref())
+        .and_then(|s| s.file_path())
+        .and_then(|p| p.parent())
+    {
+        time_map.resolve_paths(dir);
+    }
+    let time_maps = TimeMapStore::new(time_map)
+        .unwrap_or_else(|e| panic!("couldn't load a configured time map: {}", e));
+
+    let mut file = File::open("schema.yaml").unwrap();
+    let mut s = String::new();
+    file.read_to_string(&mut s).unwrap();
+    let schema: Schema = serde_yaml::from_str(&s).unwrap();
+
+    rocket
+        .manage(schema)
+        .manage(db)
+        .manage(api_keys)
+        .manage(cors)
+        .manage(http_cache)
+        .manage(sachet_config)
+        .manage(sachet_admin::Warmer::default())
+        .manage(time_maps)
+        .attach(CompassConn::fairing())
+        .attach(CORS)
+        .attach(RateLimiter::new(rate_limit))
+        .attach(Compressor(compression))
+        .attach(AdHoc::on_liftoff("time map table", |rocket| {
+            Box::pin(async move {
+                if let (Some(store), Some(db)) = (
+                    rocket.state::<TimeMapStore>(),
+                    CompassConn::get_one(rocket).await,
+                ) {
+                    if let Err(e) = store.refresh(&db).await {
+                        error!("couldn't load the time_map table: {}", e);
+                    }
+                }
+            })
+        }))
+        .attach(AdHoc::on_liftoff("sachet warmer", |rocket| {
+            Box::pin(async move {
+                let range = rocket
+                    .state::<sachet::SachetConfig>()
+                    .and_then(|c| c.warm_on_launch.clone());
+                if let Some(range) = range {
+                    if let Err(e) = sachet_admin::start_warming(rocket, range).await {
+                        error!("couldn't start warming sachet packets: {}", e);
+                    }
+                }
+            })
+        }))
+        .mount(
+            "/",
+            routes![
+                eventually::search,
+                eventually::count,
+                eventually::distinct_events,
+                eventually::get_versions,
+                misc::season_day_time_map,
+                misc::season_time_map,
+                misc::time_at,
+                misc::time_map_summary,
+                misc::refresh_time_map,
+                cors_preflight,
+                rate_limited,
+                unauthorized,
+                unavailable
+            ],
+        )
+        .mount(
+            "/sachet",
+            routes![
+                sachet::get_packets,
+                sachet::stream_packets,
+                sachet::get_day,
+                sachet_admin::list_cache,
+                sachet_admin::purge_cache,
+                sachet_admin::purge_game,
+                sachet_admin::warm_game,
+                sachet_admin::warm_range,
+                sachet_admin::warm_progress
+            ],
+        )
+}
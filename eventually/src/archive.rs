@@ -0,0 +1,137 @@
+use crate::*;
+use serde_json::Value as JSONValue;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use uuid::Uuid;
+
+// archives written by the export binary are a directory of zstd compressed NDJSON chunks,
+// described by a manifest that's written last, once everything else is in place
+pub const MANIFEST_FILE: &str = "manifest.json";
+pub const ARCHIVE_FORMAT: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArchiveManifest {
+    pub format: u32,
+    // unix seconds, like _eventually_ingest_time, so it can be passed straight to --since
+    pub exported_at: i64,
+    pub since: Option<i64>,
+    // the latest ingest time (in seconds) and versions observed time (in millis) in the snapshot
+    // the archive was taken from, which the next incremental export picks up from. exported_at
+    // won't do for that, since fill stamps every event with the time it started, so a fill
+    // still running during the export commits events stamped before it
+    #[serde(default)]
+    pub max_ingest_time: Option<i64>,
+    #[serde(default)]
+    pub max_observed: Option<i64>,
+    pub documents: ArchiveSection,
+    pub versions: ArchiveSection,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ArchiveSection {
+    pub count: u64,
+    pub chunks: Vec<ArchiveChunk>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArchiveChunk {
+    pub file: String,
+    pub count: u64,
+    // of the compressed file
+    pub sha256: String,
+}
+
+// a row of the versions table, as kept in archives
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VersionRecord {
+    pub doc_id: Uuid,
+    pub object: JSONValue,
+    pub observed: Option<i64>,
+    pub hash: Option<String>,
+}
+
+impl ArchiveManifest {
+    pub fn read(dir: &Path) -> io::Result<ArchiveManifest> {
+        let file = File::open(dir.join(MANIFEST_FILE))?;
+        let manifest: ArchiveManifest = serde_json::from_reader(io::BufReader::new(file))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        if manifest.format != ARCHIVE_FORMAT {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported archive format {}", manifest.format),
+            ));
+        }
+
+        Ok(manifest)
+    }
+
+    pub fn write(&self, dir: &Path) -> io::Result<()> {
+        let file = File::create(dir.join(MANIFEST_FILE))?;
+        serde_json::to_writer_pretty(file, self)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+}
+
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// checks a chunk against the checksum the manifest has for it
+pub fn verify_chunk(dir: &Path, chunk: &ArchiveChunk) -> io::Result<()> {
+    let found = sha256_file(&dir.join(&chunk.file))?;
+    if found != chunk.sha256 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "checksum mismatch for {}: expected {}, found {}",
+                chunk.file, chunk.sha256, found
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// hashes everything written through it, so chunks don't have to be read back
+pub struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> HashingWriter<W> {
+        HashingWriter {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<String> {
+        self.inner.flush()?;
+        Ok(format!("{:x}", self.hasher.finalize()))
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,49 @@
+use clap::Parser;
+use postgres::{Client, NoTls};
+use rustventually::update_time_bounds_sql;
+
+#[derive(Parser, Debug)]
+#[clap(
+    name = "backfill_time_bounds",
+    about = "fills time_bounds from the events already in documents_millis"
+)]
+struct Args {
+    /// postgres connection string
+    #[clap(short, long, env = "DB_URL")]
+    db_url: String,
+    /// only this sim
+    #[clap(long)]
+    sim: Option<String>,
+    /// drop the existing bounds first, instead of only widening them. needed after events are
+    /// deleted or moved to another day
+    #[clap(long)]
+    rebuild: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    let args = Args::parse();
+
+    let mut db = Client::connect(&args.db_url, NoTls)?;
+    let mut trans = db.transaction()?;
+
+    if args.rebuild {
+        trans.execute(
+            "DELETE FROM time_bounds WHERE $1::text IS NULL OR sim = $1",
+            &[&args.sim],
+        )?;
+    }
+
+    let days = trans.execute(
+        update_time_bounds_sql(
+            "(SELECT object FROM documents_millis WHERE $1::text IS NULL OR object->>'sim' = $1) AS events",
+        )
+        .as_str(),
+        &[&args.sim],
+    )?;
+
+    trans.commit()?;
+    println!("updated bounds for {} days", days);
+
+    Ok(())
+}
@@ -0,0 +1,338 @@
+use chrono::prelude::*;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value as JSONValue;
+use std::collections::BTreeMap;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::FeedEvent;
+
+// page size used when paginating a query that doesn't set its own limit
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+const NO_PARAMS: [(&str, &str); 0] = [];
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Decode(#[from] serde_json::Error),
+    #[error("eventually responded with {status}: {message}")]
+    Api { status: u16, message: String },
+}
+
+// query string for /events and /count. setters for the search fields are generated from
+// schema.yaml by build.rs; anything not covered can still be set with `param`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EventQuery {
+    params: BTreeMap<String, String>,
+}
+
+include!(concat!(env!("OUT_DIR"), "/query_params.rs"));
+
+// paging, sorting and expansion, which aren't schema fields
+macro_rules! query_params {
+    ($($method:ident: $ty:ty => $param:literal),* $(,)?) => {
+        impl EventQuery {
+            $(
+                pub fn $method(self, value: $ty) -> EventQuery {
+                    self.param($param, value)
+                }
+            )*
+        }
+    };
+}
+
+query_params! {
+    limit: usize => "limit",
+    offset: usize => "offset",
+    sort_by: &str => "sortby",
+    sort_order: &str => "sortorder",
+    expand_children: bool => "expand_children",
+    expand_parent: bool => "expand_parent",
+    expand_siblings: bool => "expand_siblings",
+    expand_depth: usize => "expand_depth",
+}
+
+impl EventQuery {
+    pub fn new() -> EventQuery {
+        EventQuery::default()
+    }
+
+    pub fn param(mut self, key: &str, value: impl ToString) -> EventQuery {
+        self.params.insert(key.to_owned(), value.to_string());
+        self
+    }
+
+    pub fn before(self, time: DateTime<Utc>) -> EventQuery {
+        self.param("before", time.timestamp_millis())
+    }
+
+    pub fn after(self, time: DateTime<Utc>) -> EventQuery {
+        self.param("after", time.timestamp_millis())
+    }
+
+    pub fn params(&self) -> &BTreeMap<String, String> {
+        &self.params
+    }
+
+    fn page_size(&self) -> usize {
+        self.params
+            .get("limit")
+            .and_then(|l| l.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+    }
+
+    fn first_page(self) -> EventQuery {
+        let page_size = self.page_size();
+        self.limit(page_size)
+    }
+
+    // the query for the page after one that returned `len` events, if there can be one
+    fn next_page(self, len: usize) -> Option<EventQuery> {
+        if len == 0 || len < self.page_size() {
+            return None;
+        }
+
+        let offset = self
+            .params
+            .get("offset")
+            .and_then(|o| o.parse::<usize>().ok())
+            .unwrap_or(0);
+        Some(self.offset(offset + len))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct TimeRange {
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+struct CountResponse {
+    count: u64,
+}
+
+fn time_path(sim: &str, season: i32, day: Option<i32>) -> String {
+    match day {
+        Some(d) => format!("/time/{}/{}/{}", sim, season, d),
+        None => format!("/time/{}/{}", sim, season),
+    }
+}
+
+fn packet_params(
+    game: Uuid,
+    from_play: Option<i64>,
+    to_play: Option<i64>,
+) -> Vec<(String, String)> {
+    let mut params = vec![("id".to_owned(), game.to_string())];
+    if let Some(from) = from_play {
+        params.push(("from_play".to_owned(), from.to_string()));
+    }
+    if let Some(to) = to_play {
+        params.push(("to_play".to_owned(), to.to_string()));
+    }
+    params
+}
+
+pub struct EventuallyClient {
+    http: reqwest::Client,
+    base_url: String,
+}
+
+impl EventuallyClient {
+    pub fn new(base_url: &str) -> EventuallyClient {
+        EventuallyClient::with_client(reqwest::Client::new(), base_url)
+    }
+
+    pub fn with_client(http: reqwest::Client, base_url: &str) -> EventuallyClient {
+        EventuallyClient {
+            http,
+            base_url: base_url.trim_end_matches('/').to_owned(),
+        }
+    }
+
+    async fn get<T: DeserializeOwned, Q: serde::Serialize + ?Sized>(
+        &self,
+        path: &str,
+        query: &Q,
+    ) -> Result<T, ClientError> {
+        let response = self
+            .http
+            .get(format!("{}{}", self.base_url, path))
+            .query(query)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(ClientError::Api {
+                status: status.as_u16(),
+                message: response.text().await.unwrap_or_default(),
+            });
+        }
+
+        Ok(serde_json::from_slice(&response.bytes().await?)?)
+    }
+
+    // events come back exactly as the server has them; a FeedEvent only needs an id
+    pub async fn events(&self, query: &EventQuery) -> Result<Vec<FeedEvent>, ClientError> {
+        self.get("/events", query.params()).await
+    }
+
+    pub async fn count(&self, query: &EventQuery) -> Result<u64, ClientError> {
+        self.get::<CountResponse, _>("/count", query.params())
+            .await
+            .map(|c| c.count)
+    }
+
+    pub async fn versions(&self, id: Uuid) -> Result<Vec<FeedEvent>, ClientError> {
+        self.get("/versions", &[("id", id.to_string())]).await
+    }
+
+    pub async fn time(
+        &self,
+        sim: &str,
+        season: i32,
+        day: Option<i32>,
+    ) -> Result<TimeRange, ClientError> {
+        self.get(&time_path(sim, season, day), &NO_PARAMS).await
+    }
+
+    // packets are returned as they come, since their game update fields vary from season to season
+    pub async fn packets(
+        &self,
+        game: Uuid,
+        from_play: Option<i64>,
+        to_play: Option<i64>,
+    ) -> Result<Vec<JSONValue>, ClientError> {
+        self.get("/sachet/packets", &packet_params(game, from_play, to_play))
+            .await
+    }
+
+    // every event matching the query, fetched a page at a time as the stream is polled
+    pub fn paginate(&self, query: EventQuery) -> BoxStream<'_, Result<FeedEvent, ClientError>> {
+        stream::unfold(Some(query.first_page()), move |query| async move {
+            let query = query?;
+            let (page, next) = match self.events(&query).await {
+                Ok(page) => {
+                    let next = query.next_page(page.len());
+                    (page.into_iter().map(Ok).collect::<Vec<_>>(), next)
+                }
+                Err(e) => (vec![Err(e)], None),
+            };
+            Some((stream::iter(page), next))
+        })
+        .flatten()
+        .boxed()
+    }
+}
+
+pub struct BlockingEventuallyClient {
+    http: reqwest::blocking::Client,
+    base_url: String,
+}
+
+impl BlockingEventuallyClient {
+    pub fn new(base_url: &str) -> BlockingEventuallyClient {
+        BlockingEventuallyClient::with_client(reqwest::blocking::Client::new(), base_url)
+    }
+
+    pub fn with_client(
+        http: reqwest::blocking::Client,
+        base_url: &str,
+    ) -> BlockingEventuallyClient {
+        BlockingEventuallyClient {
+            http,
+            base_url: base_url.trim_end_matches('/').to_owned(),
+        }
+    }
+
+    fn get<T: DeserializeOwned, Q: serde::Serialize + ?Sized>(
+        &self,
+        path: &str,
+        query: &Q,
+    ) -> Result<T, ClientError> {
+        let response = self
+            .http
+            .get(format!("{}{}", self.base_url, path))
+            .query(query)
+            .send()?;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(ClientError::Api {
+                status: status.as_u16(),
+                message: response.text().unwrap_or_default(),
+            });
+        }
+
+        Ok(serde_json::from_slice(&response.bytes()?)?)
+    }
+
+    pub fn events(&self, query: &EventQuery) -> Result<Vec<FeedEvent>, ClientError> {
+        self.get("/events", query.params())
+    }
+
+    pub fn count(&self, query: &EventQuery) -> Result<u64, ClientError> {
+        self.get::<CountResponse, _>("/count", query.params())
+            .map(|c| c.count)
+    }
+
+    pub fn versions(&self, id: Uuid) -> Result<Vec<FeedEvent>, ClientError> {
+        self.get("/versions", &[("id", id.to_string())])
+    }
+
+    pub fn time(&self, sim: &str, season: i32, day: Option<i32>) -> Result<TimeRange, ClientError> {
+        self.get(&time_path(sim, season, day), &NO_PARAMS)
+    }
+
+    pub fn packets(
+        &self,
+        game: Uuid,
+        from_play: Option<i64>,
+        to_play: Option<i64>,
+    ) -> Result<Vec<JSONValue>, ClientError> {
+        self.get("/sachet/packets", &packet_params(game, from_play, to_play))
+    }
+
+    pub fn paginate(&self, query: EventQuery) -> Pages<'_> {
+        Pages {
+            client: self,
+            query: Some(query.first_page()),
+            page: Vec::new().into_iter(),
+        }
+    }
+}
+
+// iterator over every event matching a query, for the blocking client
+pub struct Pages<'a> {
+    client: &'a BlockingEventuallyClient,
+    query: Option<EventQuery>,
+    page: std::vec::IntoIter<FeedEvent>,
+}
+
+impl<'a> Iterator for Pages<'a> {
+    type Item = Result<FeedEvent, ClientError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.page.next() {
+                return Some(Ok(event));
+            }
+
+            let query = self.query.take()?;
+            match self.client.events(&query) {
+                Ok(page) => {
+                    self.query = query.next_page(page.len());
+                    self.page = page.into_iter();
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use crate::*;
+use log::error;
+use std::io::Write;
+
+use rocket::http::ContentType;
+use rocket::tokio::task;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    // bodies smaller than this (in bytes) are sent as they are
+    pub threshold: usize,
+    pub gzip_level: u32,
+    pub brotli_quality: u32,
+    pub zstd_level: i32,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            enabled: true,
+            threshold: 1024,
+            gzip_level: 6,
+            brotli_quality: 5,
+            zstd_level: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+// in order of preference when the client weighs them equally
+const SUPPORTED_ENCODINGS: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+impl Encoding {
+    fn name(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn compress(&self, body: &[u8], config: &CompressionConfig) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut writer =
+                    brotli::CompressorWriter::new(Vec::new(), 4096, config.brotli_quality, 22);
+                writer.write_all(body)?;
+                Ok(writer.into_inner())
+            }
+            Encoding::Zstd => zstd::stream::encode_all(body, config.zstd_level),
+            Encoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(
+                    Vec::new(),
+                    flate2::Compression::new(config.gzip_level),
+                );
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+// picks the encoding with the highest q-value in an Accept-Encoding header
+fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut weights: Vec<(&str, f32)> = Vec::new();
+    for entry in accept_encoding.split(',') {
+        let mut parts = entry.split(';').map(|p| p.trim());
+        let name = match parts.next() {
+            Some(n) if !n.is_empty() => n,
+            _ => continue,
+        };
+        let q = parts
+            .find_map(|p| p.strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        weights.push((name, q));
+    }
+
+    let weight_of = |encoding: &Encoding| {
+        weights
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(encoding.name()))
+            .or_else(|| weights.iter().find(|(name, _)| *name == "*"))
+            .map(|(_, q)| *q)
+            .unwrap_or(0.0)
+    };
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in SUPPORTED_ENCODINGS.iter() {
+        let q = weight_of(encoding);
+        if q > 0.0 && best.map(|(_, best_q)| q > best_q).unwrap_or(true) {
+            best = Some((*encoding, q));
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+fn is_compressible(content_type: &ContentType) -> bool {
+    if content_type.is_event_stream() {
+        return false;
+    }
+
+    content_type.top() == "text"
+        || content_type.sub().as_str().contains("json")
+        || content_type.sub().as_str().contains("xml")
+        || content_type.sub().as_str().contains("javascript")
+}
+
+pub struct Compressor(pub CompressionConfig);
+
+#[rocket::async_trait]
+impl Fairing for Compressor {
+    fn info(&self) -> fairing::Info {
+        fairing::Info {
+            name: "Response compression",
+            kind: fairing::Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, response: &mut Response<'r>) {
+        if !self.0.enabled
+            || response.status() != Status::Ok
+            || response.headers().contains("Content-Encoding")
+            || !response
+                .content_type()
+                .map(|ct| is_compressible(&ct))
+                .unwrap_or(false)
+        {
+            return;
+        }
+
+        response.adjoin_header(Header::new("Vary", "Accept-Encoding"));
+
+        // streamed bodies have no preset size, and are left alone
+        match response.body().preset_size() {
+            Some(size) if size >= self.0.threshold => {}
+            _ => return,
+        }
+
+        let encoding = match req.headers().get_one("Accept-Encoding").and_then(negotiate) {
+            Some(e) => e,
+            None => return,
+        };
+
+        let body = match response.body_mut().to_bytes().await {
+            Ok(b) => b,
+            Err(e) => {
+                error!("couldn't read response body for compression: {}", e);
+                return;
+            }
+        };
+
+        let config = self.0.clone();
+        let compressed = task::spawn_blocking(move || {
+            let compressed = encoding.compress(&body, &config);
+            (body, compressed)
+        })
+        .await;
+
+        match compressed {
+            Ok((_, Ok(compressed))) => {
+                response.set_header(Header::new("Content-Encoding", encoding.name()));
+                response.set_sized_body(compressed.len(), Cursor::new(compressed));
+            }
+            Ok((body, Err(e))) => {
+                error!("couldn't compress response with {}: {}", encoding.name(), e);
+                response.set_sized_body(body.len(), Cursor::new(body));
+            }
+            Err(e) => {
+                // the body went down with the task, so there's nothing left to send
+                error!("compression task failed: {}", e);
+                response.set_status(Status::InternalServerError);
+                response.set_sized_body(0, Cursor::new(Vec::new()));
+            }
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use crate::*;
+
+use rocket::options;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CorsConfig {
+    // exact origins, "*", or wildcard subdomains like "https://*.sibr.dev"
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub expose_headers: Vec<String>,
+    pub max_age: u64,
+    pub allow_credentials: bool,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: vec!["*".to_owned()],
+            allowed_methods: vec!["GET".to_owned()],
+            allowed_headers: vec!["*".to_owned()],
+            expose_headers: vec![
+                "X-RateLimit-Limit".to_owned(),
+                "X-RateLimit-Remaining".to_owned(),
+                "X-RateLimit-Reset".to_owned(),
+                "Retry-After".to_owned(),
+            ],
+            max_age: 86400,
+            allow_credentials: false,
+        }
+    }
+}
+
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    if pattern.eq_ignore_ascii_case(origin) {
+        return true;
+    }
+
+    match pattern.split_once("://*.") {
+        Some((scheme, domain)) => origin
+            .split_once("://")
+            .filter(|(o_scheme, _)| o_scheme.eq_ignore_ascii_case(scheme))
+            .map(|(_, host)| {
+                let host = host.to_ascii_lowercase();
+                let domain = domain.to_ascii_lowercase();
+                host.len() > domain.len() + 1
+                    && host.ends_with(&domain)
+                    && host[..host.len() - domain.len()].ends_with('.')
+            })
+            .unwrap_or(false),
+        None => false,
+    }
+}
+
+impl CorsConfig {
+    fn is_wildcard(&self) -> bool {
+        self.allowed_origins.iter().any(|o| o == "*")
+    }
+
+    // browsers refuse credentialed responses to a wildcard origin, so that's a mistake in the
+    // config rather than something to work around by echoing every origin back
+    pub fn validate(&self) -> Result<(), String> {
+        if self.is_wildcard() && self.allow_credentials {
+            return Err(
+                "cors.allowed_origins can't include \"*\" when cors.allow_credentials is set; \
+                list the origins instead"
+                    .to_owned(),
+            );
+        }
+        Ok(())
+    }
+
+    // value for Access-Control-Allow-Origin, if this origin is allowed at all
+    fn allow_origin(&self, origin: Option<&str>) -> Option<String> {
+        if self.is_wildcard() {
+            return Some("*".to_owned());
+        }
+
+        origin
+            .filter(|o| self.allowed_origins.iter().any(|p| origin_matches(p, o)))
+            .map(|o| o.to_owned())
+    }
+
+    fn headers(&self, req: &Request<'_>) -> Vec<Header<'static>> {
+        let mut headers = Vec::new();
+
+        if !self.is_wildcard() {
+            headers.push(Header::new("Vary", "Origin"));
+        }
+
+        let origin = match self.allow_origin(req.headers().get_one("Origin")) {
+            Some(o) => o,
+            None => return headers,
+        };
+
+        headers.push(Header::new("Access-Control-Allow-Origin", origin));
+        headers.push(Header::new(
+            "Access-Control-Allow-Methods",
+            self.allowed_methods.join(", "),
+        ));
+
+        let allowed_headers =
+            if self.allow_credentials && self.allowed_headers.iter().any(|h| h == "*") {
+                req.headers()
+                    .get_one("Access-Control-Request-Headers")
+                    .unwrap_or("")
+                    .to_owned()
+            } else {
+                self.allowed_headers.join(", ")
+            };
+        if !allowed_headers.is_empty() {
+            headers.push(Header::new("Access-Control-Allow-Headers", allowed_headers));
+        }
+
+        if !self.expose_headers.is_empty() {
+            headers.push(Header::new(
+                "Access-Control-Expose-Headers",
+                self.expose_headers.join(", "),
+            ));
+        }
+
+        if self.allow_credentials {
+            headers.push(Header::new("Access-Control-Allow-Credentials", "true"));
+        }
+
+        headers
+    }
+}
+
+fn config_for(req: &Request<'_>) -> CorsConfig {
+    req.rocket()
+        .state::<CorsConfig>()
+        .cloned()
+        .unwrap_or_default()
+}
+
+pub struct CORS;
+#[rocket::async_trait]
+impl Fairing for CORS {
+    fn info(&self) -> fairing::Info {
+        fairing::Info {
+            name: "CORS headers",
+            kind: fairing::Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, response: &mut Response<'r>) {
+        for header in config_for(req).headers(req) {
+            if header.name() == "Vary" {
+                response.adjoin_header(header);
+            } else {
+                response.set_header(header);
+            }
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> response::Responder<'r, 'static> for CORS {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let config = config_for(req);
+        let mut response = Response::build();
+        for header in config.headers(req) {
+            response.header(header);
+        }
+
+        response
+            .header(Header::new(
+                "Access-Control-Max-Age",
+                config.max_age.to_string(),
+            ))
+            .header(Header::new(
+                "Allow",
+                format!("OPTIONS, {}", config.allowed_methods.join(", ")),
+            ))
+            .status(Status::NoContent)
+            .ok()
+    }
+}
+
+#[options("/<_..>")]
+pub async fn cors_preflight() -> CORS {
+    CORS
+}
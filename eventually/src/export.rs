@@ -0,0 +1,242 @@
+use chrono::prelude::*;
+use clap::Parser;
+use log::info;
+use postgres::{Client, IsolationLevel, NoTls};
+use rustventually::{
+    ArchiveChunk, ArchiveManifest, ArchiveSection, HashingWriter, VersionRecord, ARCHIVE_FORMAT,
+    MANIFEST_FILE,
+};
+use serde::Serialize;
+use serde_json::Value as JSONValue;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+#[derive(Parser, Debug)]
+#[clap(
+    name = "export",
+    about = "writes documents_millis and versions into a chunked archive that fill can load"
+)]
+struct Args {
+    /// directory to write the archive into
+    #[clap(short, long)]
+    output: PathBuf,
+    /// postgres connection string
+    #[clap(short, long, env = "DB_URL")]
+    db_url: String,
+    /// rows per chunk file
+    #[clap(long, default_value = "100000")]
+    chunk_size: u64,
+    /// zstd compression level
+    #[clap(long, default_value = "3")]
+    level: i32,
+    /// only export events ingested at or after this unix timestamp (in seconds), and versions
+    /// observed since then
+    #[clap(long)]
+    since: Option<i64>,
+    /// only export what's new since the archive with this manifest was taken. this overlaps a
+    /// little with that archive, which fill doesn't mind
+    #[clap(long)]
+    since_manifest: Option<PathBuf>,
+}
+
+// rows fetched from the portal at a time
+const FETCH_SIZE: i32 = 10_000;
+
+const INGEST_TIME: &str = "(object #>> '{metadata,_eventually_ingest_time}')::bigint";
+const HAS_INGEST_TIME: &str =
+    "jsonb_typeof(object #> '{metadata,_eventually_ingest_time}') = 'number'";
+
+struct ChunkWriter<'a> {
+    dir: &'a Path,
+    prefix: &'static str,
+    chunk_size: u64,
+    level: i32,
+    current: Option<(zstd::Encoder<'static, HashingWriter<File>>, String, u64)>,
+    section: ArchiveSection,
+}
+
+impl<'a> ChunkWriter<'a> {
+    fn new(dir: &'a Path, prefix: &'static str, args: &Args) -> ChunkWriter<'a> {
+        ChunkWriter {
+            dir,
+            prefix,
+            chunk_size: args.chunk_size.max(1),
+            level: args.level,
+            current: None,
+            section: ArchiveSection::default(),
+        }
+    }
+
+    fn write<T: Serialize>(&mut self, record: &T) -> anyhow::Result<()> {
+        if self.current.is_none() {
+            let file = format!(
+                "{}-{:05}.ndjson.zst",
+                self.prefix,
+                self.section.chunks.len()
+            );
+            let encoder = zstd::Encoder::new(
+                HashingWriter::new(File::create(self.dir.join(&file))?),
+                self.level,
+            )?;
+            self.current = Some((encoder, file, 0));
+        }
+
+        let full = match self.current.as_mut() {
+            Some((encoder, _, count)) => {
+                serde_json::to_writer(&mut *encoder, record)?;
+                encoder.write_all(b"\n")?;
+                *count += 1;
+                *count >= self.chunk_size
+            }
+            None => unreachable!(),
+        };
+        self.section.count += 1;
+
+        if full {
+            self.close_chunk()?;
+        }
+        Ok(())
+    }
+
+    fn close_chunk(&mut self) -> anyhow::Result<()> {
+        if let Some((encoder, file, count)) = self.current.take() {
+            let sha256 = encoder.finish()?.finish()?;
+            info!("wrote {} ({} rows)", file, count);
+            self.section.chunks.push(ArchiveChunk {
+                file,
+                count,
+                sha256,
+            });
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> anyhow::Result<ArchiveSection> {
+        self.close_chunk()?;
+        Ok(self.section)
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    let args = Args::parse();
+
+    // ingest time for documents (in seconds), and observed time for versions (in millis)
+    let (since, observed_since) = match (&args.since, &args.since_manifest) {
+        (Some(_), Some(_)) => anyhow::bail!("pass either --since or --since-manifest, not both"),
+        (Some(since), None) => (Some(*since), Some(since * 1000)),
+        (None, Some(path)) => {
+            let dir = if path.ends_with(MANIFEST_FILE) {
+                path.parent().unwrap_or_else(|| Path::new("."))
+            } else {
+                path.as_path()
+            };
+            // an archive of empty tables (or one from before these were recorded) leaves
+            // nothing to pick up from, so everything goes
+            let manifest = ArchiveManifest::read(dir)?;
+            (manifest.max_ingest_time, manifest.max_observed)
+        }
+        (None, None) => (None, None),
+    };
+
+    fs::create_dir_all(&args.output)?;
+    if args.output.join(MANIFEST_FILE).exists() {
+        anyhow::bail!("{} already holds an archive", args.output.display());
+    }
+
+    let exported_at = Utc::now().timestamp();
+
+    let mut db = Client::connect(&args.db_url, NoTls)?;
+    let mut trans = db
+        .build_transaction()
+        .isolation_level(IsolationLevel::RepeatableRead)
+        .read_only(true)
+        .start()?;
+
+    // read in the same snapshot as everything below, so the next export starts where this one
+    // ends
+    let max_ingest_time = trans
+        .query_one(
+            format!(
+                "SELECT max({}) FROM documents_millis WHERE {}",
+                INGEST_TIME, HAS_INGEST_TIME
+            )
+            .as_str(),
+            &[],
+        )?
+        .get::<usize, Option<i64>>(0);
+    let max_observed = trans
+        .query_one("SELECT max(observed) FROM versions", &[])?
+        .get::<usize, Option<i64>>(0);
+
+    let mut documents = ChunkWriter::new(&args.output, "documents", &args);
+    let portal = match since {
+        Some(since) => trans.bind(
+            format!(
+                "SELECT object FROM documents_millis WHERE {} AND {} >= $1",
+                HAS_INGEST_TIME, INGEST_TIME
+            )
+            .as_str(),
+            &[&since],
+        )?,
+        None => trans.bind("SELECT object FROM documents_millis", &[])?,
+    };
+    loop {
+        let rows = trans.query_portal(&portal, FETCH_SIZE)?;
+        if rows.is_empty() {
+            break;
+        }
+        for row in rows {
+            documents.write(&row.get::<&str, JSONValue>("object"))?;
+        }
+    }
+    let documents = documents.finish()?;
+
+    let mut versions = ChunkWriter::new(&args.output, "versions", &args);
+    let portal = match observed_since {
+        Some(observed) => trans.bind(
+            "SELECT doc_id, object, observed, hash FROM versions WHERE observed >= $1",
+            &[&observed],
+        )?,
+        None => trans.bind("SELECT doc_id, object, observed, hash FROM versions", &[])?,
+    };
+    loop {
+        let rows = trans.query_portal(&portal, FETCH_SIZE)?;
+        if rows.is_empty() {
+            break;
+        }
+        for row in rows {
+            versions.write(&VersionRecord {
+                doc_id: row.get::<&str, Uuid>("doc_id"),
+                object: row.get("object"),
+                observed: row.get("observed"),
+                hash: row.get("hash"),
+            })?;
+        }
+    }
+    let versions = versions.finish()?;
+
+    trans.commit()?;
+
+    let manifest = ArchiveManifest {
+        format: ARCHIVE_FORMAT,
+        exported_at,
+        since,
+        max_ingest_time,
+        max_observed,
+        documents,
+        versions,
+    };
+    manifest.write(&args.output)?;
+
+    println!(
+        "exported {} documents and {} versions to {}",
+        manifest.documents.count,
+        manifest.versions.count,
+        args.output.display()
+    );
+
+    Ok(())
+}
@@ -1,38 +1,635 @@
+use anyhow::Context;
 use chrono::prelude::*;
-use postgres::{Client, NoTls};
-use serde_json::{json, Value};
-use std::env;
-use std::fs::File;
-use std::io::prelude::*;
-use std::io::BufReader;
+use clap::Parser;
+use flate2::read::MultiGzDecoder;
+use log::{info, warn};
+use postgres::binary_copy::BinaryCopyInWriter;
+use postgres::types::Type;
+use postgres::{Client, NoTls, Transaction};
+use rayon::prelude::*;
+use rustventually::sachet::bump_game_generation;
+use rustventually::{
+    same_event_sql, update_time_bounds_sql, verify_chunk, ArchiveManifest, ConflictMode, FeedEvent,
+    Normalizer, VersionRecord, MANIFEST_FILE,
+};
+use serde::de::{self, DeserializeSeed, SeqAccess, Visitor};
+use serde_json::Value as JSONValue;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, prelude::*, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread;
 use uuid::Uuid;
 
-fn main() -> std::io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let file = File::open(&args[1])?;
-    let reader = BufReader::new(file);
-    let mut client = Client::connect(&args[2], NoTls).unwrap();
+#[derive(Parser, Debug)]
+#[clap(name = "fill", about = "bulk loads event dumps into documents_millis")]
+struct Args {
+    /// NDJSON or JSON array files, optionally gzip or zstd compressed, or directories of them.
+    /// archives written by export (or their manifest.json) load both events and versions.
+    /// `-` reads from stdin. can be given more than once
+    #[clap(short, long = "input", required = true)]
+    inputs: Vec<PathBuf>,
+    /// postgres connection string
+    #[clap(short, long, env = "DB_URL")]
+    db_url: Option<String>,
+    /// rows written (and committed) per batch
+    #[clap(long, default_value = "5000")]
+    commit_interval: usize,
+    /// skip this many events (counted across every input, in order), to pick an import back up
+    /// where a failed one stopped
+    #[clap(long, default_value = "0")]
+    resume_from: usize,
+    /// parse and validate the input without touching the database
+    #[clap(long)]
+    dry_run: bool,
+    /// recorded as _eventually_ingest_source on events that don't carry one yet
+    #[clap(long, default_value = "fill")]
+    source: String,
+    /// what to do with events that are already indexed: version (overwrite, keeping the old and
+    /// new copies in versions when they differ meaningfully), overwrite, or skip-existing.
+    /// version is the default so a fill never loses what was indexed before it
+    #[clap(long, default_value = "version")]
+    on_conflict: ConflictMode,
+}
+
+#[derive(Default, Debug)]
+struct Summary {
+    valid: u64,
+    inserted: u64,
+    updated: u64,
+    changed: u64,
+    skipped: u64,
+    rejected: u64,
+    // archived versions read, and how many of them weren't already there
+    version_rows: u64,
+    versions: u64,
+}
+
+struct Row {
+    index: usize,
+    id: Uuid,
+    object: JSONValue,
+}
+
+enum Raw {
+    Line(String),
+    Value(JSONValue),
+    Invalid(String),
+}
+
+// an event as read from an input, numbered by its position across all of them
+struct Record {
+    index: usize,
+    raw: Raw,
+}
+
+type Parsed = Result<Row, (usize, String)>;
+
+fn parse_record(record: Record, normalizer: &Normalizer) -> Parsed {
+    let index = record.index;
+    let raw = match record.raw {
+        Raw::Line(line) => serde_json::from_str(&line).map_err(|e| e.to_string()),
+        Raw::Value(value) => Ok(value),
+        Raw::Invalid(e) => Err(e),
+    };
+
+    raw.and_then(|raw| normalizer.normalize(raw).map_err(|e| e.to_string()))
+        .map(|event| Row {
+            index,
+            id: event.id,
+            object: event.to_stored(),
+        })
+        .map_err(|e| (index, e))
+}
+
+enum Input {
+    Stdin,
+    File(PathBuf),
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Input::Stdin => write!(f, "stdin"),
+            Input::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+const INPUT_EXTENSIONS: [&str; 6] = ["json", "ndjson", "jsonl", "gz", "zst", "zstd"];
+
+// what to read: event files, plus the versions chunks of any export archives among them
+#[derive(Default)]
+struct Inputs {
+    events: Vec<Input>,
+    versions: Vec<PathBuf>,
+}
+
+// an archive written by the export binary. every chunk is checked against the manifest up front,
+// so a damaged archive is caught before anything is written
+fn add_archive(dir: &Path, inputs: &mut Inputs) -> io::Result<()> {
+    let manifest = ArchiveManifest::read(dir)?;
+    for chunk in manifest
+        .documents
+        .chunks
+        .iter()
+        .chain(manifest.versions.chunks.iter())
+    {
+        verify_chunk(dir, chunk)?;
+    }
+
+    inputs.events.extend(
+        manifest
+            .documents
+            .chunks
+            .iter()
+            .map(|c| Input::File(dir.join(&c.file))),
+    );
+    inputs
+        .versions
+        .extend(manifest.versions.chunks.iter().map(|c| dir.join(&c.file)));
+    Ok(())
+}
+
+// files inside directories are imported in name order, so resuming sees the same sequence
+fn walk(dir: &Path, inputs: &mut Inputs) -> io::Result<()> {
+    if dir.join(MANIFEST_FILE).is_file() {
+        return add_archive(dir, inputs);
+    }
+
+    let mut entries = fs::read_dir(dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            walk(&path, inputs)?;
+        } else if path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| INPUT_EXTENSIONS.contains(&e))
+            .unwrap_or(false)
+        {
+            inputs.events.push(Input::File(path));
+        }
+    }
+
+    Ok(())
+}
+
+fn collect_inputs(paths: &[PathBuf]) -> io::Result<Inputs> {
+    let mut inputs = Inputs::default();
+    for path in paths {
+        if path.as_os_str() == "-" {
+            inputs.events.push(Input::Stdin);
+        } else if path.is_dir() {
+            walk(path, &mut inputs)?;
+        } else if path.ends_with(MANIFEST_FILE) {
+            add_archive(path.parent().unwrap_or_else(|| Path::new(".")), &mut inputs)?;
+        } else {
+            inputs.events.push(Input::File(path.clone()));
+        }
+    }
+    Ok(inputs)
+}
+
+// compression is detected from the stream itself, so it works the same for stdin
+fn open(input: &Input) -> io::Result<Box<dyn BufRead + Send>> {
+    let source: Box<dyn Read + Send> = match input {
+        Input::Stdin => Box::new(io::stdin()),
+        Input::File(path) => Box::new(File::open(path)?),
+    };
+    let mut reader = BufReader::new(source);
+
+    let magic = reader.fill_buf()?;
+    if magic.starts_with(&[0x1f, 0x8b]) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Ok(Box::new(BufReader::new(
+            zstd::stream::read::Decoder::with_buffer(reader)?,
+        )))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+// whether the first thing in the stream is a top-level array, rather than NDJSON
+fn starts_with_array(reader: &mut dyn BufRead) -> io::Result<bool> {
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(false);
+        }
+        match buf.iter().position(|b| !b.is_ascii_whitespace()) {
+            Some(i) => return Ok(buf[i] == b'['),
+            None => {
+                let len = buf.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+// reads records, parses them a chunk at a time across rayon's pool, and hands them to the writer
+struct Producer {
+    normalizer: Normalizer,
+    resume_from: usize,
+    chunk_size: usize,
+    next_index: usize,
+    pending: Vec<Record>,
+    tx: SyncSender<anyhow::Result<Vec<Parsed>>>,
+}
+
+impl Producer {
+    // false once the writer has gone away
+    fn push(&mut self, raw: Raw) -> bool {
+        let index = self.next_index;
+        self.next_index += 1;
+        if index < self.resume_from {
+            return true;
+        }
+
+        self.pending.push(Record { index, raw });
+        if self.pending.len() >= self.chunk_size {
+            self.flush()
+        } else {
+            true
+        }
+    }
+
+    fn flush(&mut self) -> bool {
+        if self.pending.is_empty() {
+            return true;
+        }
+
+        let normalizer = &self.normalizer;
+        let parsed = std::mem::take(&mut self.pending)
+            .into_par_iter()
+            .map(|record| parse_record(record, normalizer))
+            .collect();
+        self.tx.send(Ok(parsed)).is_ok()
+    }
+
+    fn read(&mut self, input: &Input) -> anyhow::Result<()> {
+        let mut reader = open(input)?;
+
+        if starts_with_array(&mut reader)? {
+            let mut de = serde_json::Deserializer::from_reader(reader);
+            ArrayElements(self).deserialize(&mut de)?;
+            de.end()?;
+            return Ok(());
+        }
 
-    let mut trans = client.transaction().unwrap();
+        for line in reader.split(b'\n') {
+            let line = line?;
+            if line.iter().all(|b| b.is_ascii_whitespace()) {
+                continue;
+            }
+            let raw = match String::from_utf8(line) {
+                Ok(l) => Raw::Line(l),
+                Err(e) => Raw::Invalid(e.to_string()),
+            };
+            if !self.push(raw) {
+                break;
+            }
+        }
 
-    let statement = trans
-        .prepare("INSERT INTO documents_millis (doc_id, object) VALUES ($1,$2) ON CONFLICT (doc_id) DO UPDATE SET object = $2")
-        .unwrap();
+        Ok(())
+    }
+}
+
+// streams the elements of a top-level array into the producer, without holding the whole array
+struct ArrayElements<'a>(&'a mut Producer);
+
+impl<'de, 'a> DeserializeSeed<'de> for ArrayElements<'a> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for ArrayElements<'a> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "an array of events")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(value) = seq.next_element::<JSONValue>()? {
+            if !self.0.push(Raw::Value(value)) {
+                return Err(de::Error::custom("the writer stopped"));
+            }
+        }
+        Ok(())
+    }
+}
+
+// archives the old and new copies of staged events that changed meaningfully, using the same
+// comparison as the monitor
+fn archive_changes(trans: &mut Transaction, summary: &mut Summary) -> Result<(), postgres::Error> {
+    let rows = trans.query(
+        format!(
+            "WITH staged AS (
+                SELECT DISTINCT ON (doc_id) doc_id, object FROM fill_staging ORDER BY doc_id, line DESC
+            ), changed AS (
+                SELECT s.doc_id, d.object AS old_object, s.object AS new_object
+                FROM staged s JOIN documents_millis d ON d.doc_id = s.doc_id
+                WHERE NOT {} AND NOT EXISTS (SELECT 1 FROM versions v WHERE v.doc_id = s.doc_id AND {})
+            )
+            INSERT INTO versions (doc_id, object, observed, hash)
+                SELECT doc_id, object, $1, encode(sha256(convert_to((object #>> '{{}}'), 'UTF8')), 'hex')
+                FROM (
+                    SELECT doc_id, old_object AS object FROM changed
+                    UNION ALL SELECT doc_id, new_object AS object FROM changed
+                ) copies
+            RETURNING doc_id",
+            same_event_sql("d.object", "s.object"),
+            same_event_sql("v.object", "s.object")
+        )
+        .as_str(),
+        &[&Utc::now().timestamp_millis()],
+    )?;
+
+    let changed = rows
+        .iter()
+        .map(|row| row.get::<&str, Uuid>("doc_id"))
+        .collect::<HashSet<_>>();
+    summary.changed += changed.len() as u64;
+
+    Ok(())
+}
+
+// COPYs a batch into a staging table, then upserts it into documents_millis in one statement
+fn write_batch(
+    db: &mut Client,
+    batch: &[Row],
+    mode: ConflictMode,
+    summary: &mut Summary,
+) -> Result<(), postgres::Error> {
+    let mut trans = db.transaction()?;
+
+    let sink = trans.copy_in("COPY fill_staging (line, doc_id, object) FROM STDIN BINARY")?;
+    let mut writer = BinaryCopyInWriter::new(sink, &[Type::INT8, Type::UUID, Type::JSONB]);
+    for row in batch {
+        writer.write(&[&(row.index as i64), &row.id, &row.object])?;
+    }
+    writer.finish()?;
+
+    if mode == ConflictMode::Version {
+        archive_changes(&mut trans, summary)?;
+    }
+
+    let on_conflict = match mode {
+        ConflictMode::SkipExisting => "DO NOTHING",
+        _ => "DO UPDATE SET object = EXCLUDED.object",
+    };
+
+    // an id can only be upserted once per statement, so the last copy in the batch wins
+    let staged = trans
+        .query_one("SELECT count(DISTINCT doc_id) FROM fill_staging", &[])?
+        .get::<usize, i64>(0) as u64;
+    // time bounds only grow from rows that were actually written, not ones the conflict mode
+    // left alone
+    let rows = trans.query(
+        format!(
+            "WITH written AS (
+                INSERT INTO documents_millis (doc_id, object)
+                    SELECT DISTINCT ON (doc_id) doc_id, object FROM fill_staging ORDER BY doc_id, line DESC
+                    ON CONFLICT (doc_id) {}
+                    RETURNING object, (xmax = 0) AS inserted
+            ), bounds AS ({})
+            SELECT inserted FROM written",
+            on_conflict,
+            update_time_bounds_sql("written")
+        )
+        .as_str(),
+        &[],
+    )?;
+
+    // anything written may change a game's packets, whichever mode wrote it, so the cached
+    // sachet packets for every game the written rows are tagged in are out of date now
+    summary.skipped += staged - rows.len() as u64;
+    let mut games = HashSet::new();
+    for row in rows {
+        if row.get::<&str, bool>("inserted") {
+            summary.inserted += 1;
+        } else {
+            summary.updated += 1;
+        }
+        if let Ok(event) = FeedEvent::try_from(row.get::<&str, JSONValue>("object")) {
+            games.extend(event.game_ids());
+        }
+    }
 
-    for (i, l) in reader.lines().enumerate() {
-        println!("#{}", i);
-        let mut v: Value = serde_json::from_str(&l.unwrap()).unwrap();
-        let uuid = Uuid::parse_str(v["id"].as_str().unwrap()).unwrap();
-        // v["created"] = json!(v["created"]
-        //     .as_str()
-        //     .unwrap()
-        //     .parse::<DateTime<Utc>>()
-        //     .unwrap()
-        //     .timestamp());
-        trans.execute(&statement, &[&uuid, &v]).unwrap();
+    for game in games {
+        bump_game_generation(&mut trans, &game)?;
     }
 
-    trans.commit().unwrap();
+    trans.commit()
+}
+
+// loads archived versions, leaving out ones that are already there so an archive can be loaded
+// into the same instance twice
+fn write_versions(db: &mut Client, batch: &[VersionRecord]) -> Result<u64, postgres::Error> {
+    let mut trans = db.transaction()?;
+
+    let sink = trans
+        .copy_in("COPY fill_versions_staging (doc_id, object, observed, hash) FROM STDIN BINARY")?;
+    let mut writer =
+        BinaryCopyInWriter::new(sink, &[Type::UUID, Type::JSONB, Type::INT8, Type::TEXT]);
+    for version in batch {
+        writer.write(&[
+            &version.doc_id,
+            &version.object,
+            &version.observed,
+            &version.hash,
+        ])?;
+    }
+    writer.finish()?;
+
+    let written = trans.execute(
+        "INSERT INTO versions (doc_id, object, observed, hash)
+            SELECT DISTINCT s.doc_id, s.object, s.observed, s.hash FROM fill_versions_staging s
+            WHERE NOT EXISTS (
+                SELECT 1 FROM versions v
+                WHERE v.doc_id = s.doc_id
+                AND v.observed IS NOT DISTINCT FROM s.observed
+                AND v.hash IS NOT DISTINCT FROM s.hash
+            )",
+        &[],
+    )?;
+
+    trans.commit()?;
+    Ok(written)
+}
+
+fn load_versions(
+    mut db: Option<&mut Client>,
+    chunks: &[PathBuf],
+    commit_interval: usize,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    let mut batch = Vec::with_capacity(commit_interval);
+    let mut flush = |batch: &mut Vec<VersionRecord>, summary: &mut Summary| -> anyhow::Result<()> {
+        if let Some(db) = db.as_mut() {
+            summary.versions += write_versions(db, batch)?;
+        }
+        batch.clear();
+        Ok(())
+    };
+
+    for chunk in chunks {
+        info!("reading versions from {}", chunk.display());
+        let reader = open(&Input::File(chunk.clone()))?;
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            batch.push(
+                serde_json::from_str::<VersionRecord>(&line)
+                    .with_context(|| format!("malformed version in {}", chunk.display()))?,
+            );
+            summary.version_rows += 1;
+            if batch.len() >= commit_interval {
+                flush(&mut batch, summary)?;
+            }
+        }
+    }
+
+    flush(&mut batch, summary)
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    let args = Args::parse();
+
+    let mut db = if args.dry_run {
+        None
+    } else {
+        let url = args.db_url.as_deref().ok_or_else(|| {
+            anyhow::anyhow!("--db-url (or DB_URL) is needed unless --dry-run is set")
+        })?;
+        let mut client = Client::connect(url, NoTls)?;
+        client.batch_execute(
+            "CREATE TEMP TABLE fill_staging (line bigint, doc_id uuid, object jsonb) ON COMMIT DELETE ROWS;
+            CREATE TEMP TABLE fill_versions_staging (doc_id uuid, object jsonb, observed bigint, hash text) ON COMMIT DELETE ROWS;",
+        )?;
+        Some(client)
+    };
+
+    let Inputs { events, versions } = collect_inputs(&args.inputs)?;
+    let commit_interval = args.commit_interval.max(1);
+
+    let (tx, rx) = sync_channel(4);
+    let mut producer = Producer {
+        // dumps of our own index already say where their events came from
+        normalizer: Normalizer::new(&args.source).keep_existing_stamps(),
+        resume_from: args.resume_from,
+        chunk_size: commit_interval,
+        next_index: 0,
+        pending: Vec::new(),
+        tx,
+    };
+    let reader = thread::spawn(move || {
+        for input in events {
+            info!("reading {}", input);
+            if let Err(e) = producer.read(&input) {
+                let _ = producer
+                    .tx
+                    .send(Err(e.context(format!("couldn't read {}", input))));
+                return;
+            }
+        }
+        producer.flush();
+    });
+
+    let mut summary = Summary::default();
+    let mut batch: Vec<Row> = Vec::with_capacity(commit_interval);
+    // every event before this one has been committed
+    let mut committed = args.resume_from;
+    let mut seen = args.resume_from;
+
+    for chunk in rx {
+        let chunk =
+            chunk.with_context(|| format!("rerun with --resume-from {} to continue", committed))?;
+
+        for parsed in chunk {
+            match parsed {
+                Ok(row) => {
+                    summary.valid += 1;
+                    seen = row.index + 1;
+                    batch.push(row);
+                }
+                Err((index, e)) => {
+                    warn!("rejecting event {}: {}", index, e);
+                    summary.rejected += 1;
+                    seen = index + 1;
+                }
+            }
+
+            if batch.len() >= commit_interval {
+                if let Some(db) = db.as_mut() {
+                    write_batch(db, &batch, args.on_conflict, &mut summary).with_context(|| {
+                        format!(
+                            "couldn't write events {}..{}. rerun with --resume-from {} to continue",
+                            committed, seen, committed
+                        )
+                    })?;
+                    info!("committed through event {}", seen - 1);
+                }
+                batch.clear();
+                committed = seen;
+            }
+        }
+    }
+
+    if let Some(db) = db.as_mut() {
+        if !batch.is_empty() {
+            write_batch(db, &batch, args.on_conflict, &mut summary).with_context(|| {
+                format!(
+                    "couldn't write the last batch. rerun with --resume-from {} to continue",
+                    committed
+                )
+            })?;
+        }
+    }
+
+    reader
+        .join()
+        .map_err(|_| anyhow::anyhow!("the input reader panicked"))?;
+
+    // every event is in by now, so this can simply be rerun on its own if it fails
+    load_versions(db.as_mut(), &versions, commit_interval, &mut summary).with_context(|| {
+        format!(
+            "couldn't load archived versions. rerun with --resume-from {} to continue",
+            seen
+        )
+    })?;
+
+    if args.dry_run {
+        println!(
+            "dry run: {} valid, {} rejected, {} versions",
+            summary.valid, summary.rejected, summary.version_rows
+        );
+    } else {
+        println!(
+            "{} inserted, {} updated ({} changed), {} skipped, {} rejected",
+            summary.inserted, summary.updated, summary.changed, summary.skipped, summary.rejected
+        );
+        if summary.version_rows > 0 {
+            println!(
+                "{} of {} archived versions loaded",
+                summary.versions, summary.version_rows
+            );
+        }
+    }
 
     Ok(())
 }
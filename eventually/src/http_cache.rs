@@ -0,0 +1,123 @@
+use crate::*;
+use log::error;
+use sha2::{Digest, Sha256};
+
+use rocket::http::ContentType;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct HttpCacheConfig {
+    // highest season whose events are considered final, by sim
+    pub completed_seasons: HashMap<String, i64>,
+    // `before` filters further in the past than this (in seconds) are considered final too
+    pub settle_time: i64,
+    pub immutable_max_age: u64,
+    // max-age for everything else; 0 means clients always revalidate with their etag
+    pub max_age: u64,
+}
+
+impl Default for HttpCacheConfig {
+    fn default() -> Self {
+        HttpCacheConfig {
+            completed_seasons: HashMap::new(),
+            settle_time: 7 * 24 * 60 * 60,
+            immutable_max_age: 365 * 24 * 60 * 60,
+            max_age: 0,
+        }
+    }
+}
+
+impl HttpCacheConfig {
+    pub fn is_completed_season(&self, sim: &str, season: i64) -> bool {
+        self.completed_seasons
+            .get(sim)
+            .map(|completed| season <= *completed)
+            .unwrap_or(false)
+    }
+
+    // whether a search query (with `before`/`after` already converted to millis) only covers data that won't change anymore
+    pub fn is_settled(&self, query: &HashMap<String, String>) -> bool {
+        let settled_before = query
+            .get("before")
+            .and_then(|b| b.parse::<i64>().ok())
+            .map(|b| b < Utc::now().timestamp_millis() - self.settle_time * 1000)
+            .unwrap_or(false);
+
+        // seasons only mean anything within a sim, so without one the query could take in a
+        // season that's still running somewhere
+        let settled_season = query
+            .get("sim")
+            .zip(
+                query
+                    .get("season")
+                    .or_else(|| query.get("season_max"))
+                    .and_then(|s| s.parse::<i64>().ok()),
+            )
+            .map(|(sim, season)| self.is_completed_season(sim, season))
+            .unwrap_or(false);
+
+        settled_before || settled_season
+    }
+
+    pub fn cache_control(&self, immutable: bool) -> String {
+        if immutable {
+            format!("public, max-age={}, immutable", self.immutable_max_age)
+        } else if self.max_age > 0 {
+            format!("public, max-age={}", self.max_age)
+        } else {
+            "no-cache".to_owned()
+        }
+    }
+
+    pub fn wrap<T: Serialize>(&self, value: T, immutable: bool) -> Cached<T> {
+        Cached {
+            value,
+            cache_control: self.cache_control(immutable),
+        }
+    }
+}
+
+// json response with an etag derived from its body, answering matching If-None-Match requests with a 304
+pub struct Cached<T: Serialize> {
+    value: T,
+    cache_control: String,
+}
+
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    // weak comparison, so the W/ prefix doesn't matter
+    let opaque = |t: &str| t.trim().trim_start_matches("W/").to_owned();
+    if_none_match
+        .split(',')
+        .any(|t| t.trim() == "*" || opaque(t) == opaque(etag))
+}
+
+impl<'r, T: Serialize> Responder<'r, 'static> for Cached<T> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let body = serde_json::to_vec(&self.value).map_err(|e| {
+            error!("couldn't serialize response: {}", e);
+            Status::InternalServerError
+        })?;
+
+        // weak, since compression changes the bytes on the wire but not the meaning
+        let etag = format!("W/\"{:x}\"", Sha256::digest(&body));
+
+        let not_modified = req
+            .headers()
+            .get("If-None-Match")
+            .any(|v| etag_matches(v, &etag));
+
+        let mut response = Response::build();
+        response
+            .header(Header::new("ETag", etag))
+            .header(Header::new("Cache-Control", self.cache_control));
+
+        if not_modified {
+            response.status(Status::NotModified).ok()
+        } else {
+            response
+                .header(ContentType::JSON)
+                .sized_body(body.len(), Cursor::new(body))
+                .ok()
+        }
+    }
+}
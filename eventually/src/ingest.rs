@@ -0,0 +1,201 @@
+use crate::*;
+use log::warn;
+use serde_json::Value as JSONValue;
+use uuid::Uuid;
+
+// brings events from any source into the shape kept in documents_millis, stamped with where and
+// when they came from. nothing else about an event is touched
+pub struct Normalizer {
+    pub source: String,
+    pub ingest_time: i64,
+    // leave stamps already on an event alone, e.g. when reloading one of our own dumps
+    pub keep_existing_stamps: bool,
+}
+
+impl Normalizer {
+    pub fn new(source: &str) -> Normalizer {
+        Normalizer {
+            source: source.to_owned(),
+            ingest_time: Utc::now().timestamp(),
+            keep_existing_stamps: false,
+        }
+    }
+
+    pub fn keep_existing_stamps(mut self) -> Normalizer {
+        self.keep_existing_stamps = true;
+        self
+    }
+
+    pub fn stamp(&self, event: &mut FeedEvent) {
+        event.update_metadata(|m| {
+            if !self.keep_existing_stamps || m.ingest_source.is_none() {
+                m.ingest_source = Some(self.source.clone());
+            }
+            if !self.keep_existing_stamps || m.ingest_time.is_none() {
+                m.ingest_time = Some(self.ingest_time);
+            }
+        });
+    }
+
+    pub fn normalize(&self, raw: JSONValue) -> Result<FeedEvent, serde_json::Error> {
+        let mut event = serde_json::from_value::<FeedEvent>(raw)?;
+        self.stamp(&mut event);
+        Ok(event)
+    }
+}
+
+// only events without a usable id are left out, since there's nothing to store them under
+pub fn parse_events(events: Vec<JSONValue>) -> Vec<FeedEvent> {
+    events
+        .into_iter()
+        .filter_map(|e| {
+            let shown = e.to_string();
+            match serde_json::from_value::<FeedEvent>(e) {
+                Ok(event) => Some(event),
+                Err(err) => {
+                    warn!("skipping event without an id {}: {}", shown, err);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+// an event with the fields that change on their own (upscales, nuts, our ingest stamps) dropped,
+// which is what we compare to decide whether it actually changed
+pub fn comparable_sql(object: &str) -> String {
+    format!(
+        "(((({}::jsonb #- '{{metadata,scales}}') #- '{{nuts}}') #- '{{metadata,_eventually_ingest_time}}') #- '{{metadata,_eventually_ingest_source}}')",
+        object
+    )
+}
+
+// whether `a` and `b` are the same event once the noisy fields are ignored
+pub fn same_event_sql(a: &str, b: &str) -> String {
+    let (a, b) = (comparable_sql(a), comparable_sql(b));
+    format!("({a} @> {b} AND {a} <@ {b})", a = a, b = b)
+}
+
+pub const INSERT_VERSION_SQL: &str = "INSERT INTO versions (doc_id, object, observed, hash)
+    VALUES ($1, $2, $3, encode(sha256(convert_to(($2::jsonb #>> '{}'), 'UTF8')), 'hex'))";
+
+// widens time_bounds to cover the events in `source`, anything that can go in a FROM clause and
+// yields stored events as a column named `object`
+pub fn update_time_bounds_sql(source: &str) -> String {
+    format!(
+        "INSERT INTO time_bounds (sim, season, day, first_created, last_created)
+            SELECT object->>'sim', (object->>'season')::bigint, (object->>'day')::bigint,
+                min((object->>'created')::bigint), max((object->>'created')::bigint)
+            FROM {}
+            WHERE object->>'sim' IS NOT NULL
+                AND jsonb_typeof(object->'season') = 'number'
+                AND jsonb_typeof(object->'day') = 'number'
+                AND jsonb_typeof(object->'created') = 'number'
+            GROUP BY 1, 2, 3
+            ON CONFLICT (sim, season, day) DO UPDATE SET
+                first_created = LEAST(time_bounds.first_created, EXCLUDED.first_created),
+                last_created = GREATEST(time_bounds.last_created, EXCLUDED.last_created)",
+        source
+    )
+}
+
+// what to do when an incoming event is already in documents_millis
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictMode {
+    Overwrite,
+    SkipExisting,
+    // overwrite, archiving the old and new copies in versions when the event meaningfully changed
+    Version,
+}
+
+impl std::str::FromStr for ConflictMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ConflictMode, String> {
+        match s {
+            "overwrite" => Ok(ConflictMode::Overwrite),
+            "skip-existing" => Ok(ConflictMode::SkipExisting),
+            "version" => Ok(ConflictMode::Version),
+            other => Err(format!(
+                "unknown conflict mode {}; expected overwrite, skip-existing or version",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Upserted {
+    Inserted,
+    Updated,
+    // updated, and the change was recorded in versions
+    Changed,
+    Skipped,
+}
+
+pub fn upsert_event<C: postgres::GenericClient>(
+    c: &mut C,
+    id: &Uuid,
+    object: &JSONValue,
+    mode: ConflictMode,
+) -> Result<Upserted, postgres::Error> {
+    if mode == ConflictMode::SkipExisting {
+        let written = c.execute(
+            "INSERT INTO documents_millis (doc_id, object) VALUES ($1, $2) ON CONFLICT (doc_id) DO NOTHING",
+            &[id, object],
+        )?;
+        return Ok(if written > 0 {
+            Upserted::Inserted
+        } else {
+            Upserted::Skipped
+        });
+    }
+
+    let old = if mode == ConflictMode::Version {
+        c.query_opt(
+            "SELECT object FROM documents_millis WHERE doc_id = $1",
+            &[id],
+        )?
+        .map(|row| row.get::<&str, JSONValue>("object"))
+    } else {
+        None
+    };
+
+    let inserted = c
+        .query_one(
+            "INSERT INTO documents_millis (doc_id, object) VALUES ($1, $2) ON CONFLICT (doc_id) DO UPDATE SET object = $2 RETURNING (xmax=0) AS inserted",
+            &[id, object],
+        )?
+        .get::<&str, bool>("inserted");
+
+    if inserted {
+        return Ok(Upserted::Inserted);
+    }
+
+    let old = match old {
+        Some(old) => old,
+        None => return Ok(Upserted::Updated),
+    };
+
+    let changed = c
+        .query_one(
+            format!(
+                "SELECT NOT {} AND NOT EXISTS (SELECT 1 FROM versions WHERE doc_id = $1 AND {}) AS changed",
+                same_event_sql("$2", "$3"),
+                same_event_sql("object", "$3")
+            )
+            .as_str(),
+            &[id, &old, object],
+        )?
+        .get::<&str, bool>("changed");
+
+    if !changed {
+        return Ok(Upserted::Updated);
+    }
+
+    let observed = Utc::now().timestamp_millis();
+    c.execute(INSERT_VERSION_SQL, &[id, &old, &observed])?;
+    c.execute(INSERT_VERSION_SQL, &[id, object, &observed])?;
+
+    Ok(Upserted::Changed)
+}
@@ -0,0 +1,124 @@
+use log::{error, info};
+use postgres::{Client, NoTls};
+use rustventually::sachet::{store_game_update, ChronGameUpdate};
+use rustventually::EventuallyError;
+use serde_json::Value as JSONValue;
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+
+const COMMIT_EVERY: usize = 1000;
+
+#[derive(Default)]
+struct Counts {
+    written: usize,
+    unchanged: usize,
+    rejected: usize,
+}
+
+// accepts single updates, arrays of updates, and chronicler response pages ({"data": [...]}),
+// either as one json document or as newline-delimited json
+fn updates_in(value: JSONValue) -> Vec<JSONValue> {
+    match value {
+        JSONValue::Array(updates) => updates,
+        JSONValue::Object(mut obj) if !obj.contains_key("timestamp") => match obj.remove("data") {
+            Some(JSONValue::Array(updates)) => updates,
+            Some(other) => {
+                obj.insert("data".to_owned(), other);
+                vec![JSONValue::Object(obj)]
+            }
+            None => vec![JSONValue::Object(obj)],
+        },
+        other => vec![other],
+    }
+}
+
+// problems with the update itself, as opposed to with the database
+fn is_bad_update(e: &EventuallyError) -> bool {
+    match e {
+        EventuallyError::InvalidGameId | EventuallyError::SerdeJSON(_) => true,
+        // data exceptions and integrity constraint violations
+        EventuallyError::Postgres(e) => e
+            .code()
+            .map(|code| code.code().starts_with("22") || code.code().starts_with("23"))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+fn ingest_file(db: &mut Client, path: &str, counts: &mut Counts) -> anyhow::Result<()> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut trans = db.transaction()?;
+    let mut pending = 0;
+
+    for value in serde_json::Deserializer::from_reader(reader).into_iter::<JSONValue>() {
+        for raw in updates_in(value?) {
+            let update = match serde_json::from_value::<ChronGameUpdate>(raw) {
+                Ok(u) => u,
+                Err(e) => {
+                    error!("skipping malformed game update in {}: {}", path, e);
+                    counts.rejected += 1;
+                    continue;
+                }
+            };
+
+            // each update gets its own savepoint, so a row postgres refuses doesn't abort the
+            // rest of the batch
+            let mut savepoint = trans.transaction()?;
+            match store_game_update(&mut savepoint, &update) {
+                Ok(written) => {
+                    savepoint.commit()?;
+                    if written {
+                        counts.written += 1;
+                    } else {
+                        counts.unchanged += 1;
+                    }
+                }
+                Err(e) if is_bad_update(&e) => {
+                    savepoint.rollback()?;
+                    error!(
+                        "couldn't store update for game {} play {}: {}",
+                        update.data.id, update.data.play_count, e
+                    );
+                    counts.rejected += 1;
+                }
+                // anything else (a lost connection, a missing table) would fail every row
+                Err(e) => return Err(e.into()),
+            }
+
+            pending += 1;
+            if pending >= COMMIT_EVERY {
+                trans.commit()?;
+                trans = db.transaction()?;
+                pending = 0;
+            }
+        }
+    }
+
+    trans.commit()?;
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+
+    let files: Vec<String> = env::args().skip(1).collect();
+    if files.is_empty() {
+        anyhow::bail!("usage: ingest_game_updates <file>... (with DB_URL set)");
+    }
+
+    let mut db = Client::connect(&env::var("DB_URL")?, NoTls)?;
+    let mut counts = Counts::default();
+
+    for path in files {
+        info!("ingesting game updates from {}", path);
+        ingest_file(&mut db, &path, &mut counts)?;
+    }
+
+    info!(
+        "done: {} written, {} unchanged, {} rejected",
+        counts.written, counts.unchanged, counts.rejected
+    );
+
+    Ok(())
+}
@@ -4,7 +4,7 @@ use std::collections::HashMap;
 use std::io::Cursor;
 
 use rocket::fairing::{self, Fairing};
-use rocket::{http::Header, http::Status, options, response, Request, Response};
+use rocket::{http::Header, http::Status, response, Request, Response};
 
 use rocket_sync_db_pools::{database, postgres};
 
@@ -18,6 +18,41 @@ use thiserror::Error;
 mod apis;
 pub use apis::*;
 
+mod api_keys;
+pub use api_keys::*;
+
+mod rate_limit;
+pub use rate_limit::*;
+
+mod cors;
+pub use cors::*;
+
+mod http_cache;
+pub use http_cache::*;
+
+mod compression;
+pub use compression::*;
+
+mod models;
+pub use models::*;
+
+mod ingest;
+pub use ingest::*;
+
+mod archive;
+pub use archive::*;
+
+mod time_map;
+pub use time_map::*;
+
+#[cfg(feature = "client")]
+mod client;
+#[cfg(feature = "client")]
+pub use client::*;
+
+mod app;
+pub use app::*;
+
 #[derive(Debug, Clone)]
 pub struct Query(HashMap<String, String>);
 
@@ -41,42 +76,6 @@ impl<'r> FromRequest<'r> for Query {
 #[database("eventually")]
 pub struct CompassConn(postgres::Client);
 
-pub struct CORS;
-#[rocket::async_trait]
-impl Fairing for CORS {
-    fn info(&self) -> fairing::Info {
-        fairing::Info {
-            name: "CORS headers",
-            kind: fairing::Kind::Response,
-        }
-    }
-
-    async fn on_response<'r>(&self, _: &'r Request<'_>, response: &mut Response<'r>) {
-        response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
-        response.set_header(Header::new("Access-Control-Allow-Methods", "GET"));
-        response.set_header(Header::new("Access-Control-Allow-Headers", "*"));
-    }
-}
-
-#[rocket::async_trait]
-impl<'r> response::Responder<'r, 'static> for CORS {
-    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
-        Response::build()
-            .header(Header::new("Access-Control-Allow-Origin", "*"))
-            .header(Header::new("Access-Control-Allow-Methods", "GET"))
-            .header(Header::new("Access-Control-Allow-Headers", "*"))
-            .header(Header::new("Access-Control-Max-Age", "86400"))
-            .header(Header::new("Allow", "OPTIONS, GET"))
-            .status(Status::NoContent)
-            .ok()
-    }
-}
-
-#[options("/<_..>")]
-pub async fn cors_preflight() -> CORS {
-    CORS
-}
-
 #[derive(Error, Debug)]
 pub enum EventuallyError {
     #[error(transparent)]
@@ -85,25 +84,51 @@ pub enum EventuallyError {
     Compass(#[from] compass::CompassError),
     #[error(transparent)]
     SerdeJSON(#[from] serde_json::Error),
+    #[error(transparent)]
+    Postgres(#[from] postgres::Error),
     #[error("entry not found in time map")]
     TimeMapEntryNotFound,
+    #[error(transparent)]
+    TimeMap(#[from] TimeMapError),
+    #[error("invalid timestamp {0}, expected millis or RFC3339")]
+    InvalidTimestamp(String),
+    #[error("invalid game id")]
+    InvalidGameId,
+    #[error("invalid speed {0}, expected a finite number")]
+    InvalidSpeed(f64),
+    #[error("invalid range: {0}")]
+    InvalidRange(String),
+    #[error("rate limit exceeded")]
+    RateLimited,
+    #[error("invalid or missing api key")]
+    Unauthorized,
+    #[error("this api key can't do that")]
+    Forbidden,
+    #[error("packets are already being warmed")]
+    WarmerBusy,
+    #[error("couldn't get a database connection")]
+    NoDatabase,
+    #[error("server is missing its {0}")]
+    MissingState(&'static str),
+    #[error(transparent)]
+    Task(#[from] rocket::tokio::task::JoinError),
 }
 
 impl<'r> Responder<'r, 'static> for EventuallyError {
     fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let status = match self {
+            EventuallyError::RateLimited => Status::TooManyRequests,
+            EventuallyError::Unauthorized => Status::Unauthorized,
+            EventuallyError::Forbidden => Status::Forbidden,
+            EventuallyError::WarmerBusy => Status::Conflict,
+            EventuallyError::NoDatabase => Status::ServiceUnavailable,
+            EventuallyError::Task(_) => Status::InternalServerError,
+            _ => Status::BadRequest,
+        };
         let r_text = format!("{}", self);
         Response::build()
-            .status(Status::BadRequest)
+            .status(status)
             .sized_body(r_text.len(), Cursor::new(r_text))
             .ok()
     }
 }
-
-pub type TimeMap = Vec<TimeMapSeason>;
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct TimeMapSeason {
-    pub lower_bound: DateTime<Utc>,
-    pub higher_bound: DateTime<Utc>,
-    pub days: Vec<(DateTime<Utc>, DateTime<Utc>)>,
-}
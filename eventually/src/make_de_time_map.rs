@@ -1,87 +1,183 @@
-use chrono::{DateTime, Utc};
-use rustventually::{TimeMap, TimeMapSeason};
-use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use anyhow::Context;
+use chrono::{DateTime, TimeZone, Utc};
+use clap::Parser;
+use log::{info, warn};
+use postgres::{Client, NoTls};
+use rustventually::{validate_time_map, write_time_map, TimeMapBuilder};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct V1Games {
-    data: Vec<Game>,
+#[derive(Parser, Debug)]
+#[clap(
+    name = "make_time_map",
+    about = "builds a time map from indexed events, a games dump, or chronicler"
+)]
+struct Args {
+    /// derive bounds from the events in documents_millis. not read from DB_URL, since having it
+    /// set would conflict with --games and --upstream
+    #[clap(short, long)]
+    db_url: Option<String>,
+    /// a local dump of chronicler's v1 games, either the whole response or just its data array
+    #[clap(short, long)]
+    games: Option<PathBuf>,
+    /// a chronicler v1 games url to fetch, e.g.
+    /// https://api.sibr.dev/chronicler/v1/games?before=2020-10-30T21:22:59.000Z
+    #[clap(short, long)]
+    upstream: Option<String>,
+    /// sim to take events from, with --db-url
+    #[clap(long, default_value = "thisidisstaticyo")]
+    sim: String,
+    /// leave out anything after this season
+    #[clap(long)]
+    max_season: Option<i64>,
+    /// written as JSON or YAML for those extensions, bincode otherwise
+    #[clap(short, long, default_value = "time_map.json")]
+    output: PathBuf,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum GamesDump {
+    Response { data: Vec<Game> },
+    Games(Vec<Game>),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 struct Game {
-    start_time: DateTime<Utc>,
-    end_time: DateTime<Utc>,
+    game_id: Option<String>,
+    start_time: Option<DateTime<Utc>>,
+    end_time: Option<DateTime<Utc>>,
     data: GameData,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 struct GameData {
     season: i64,
     day: i64,
 }
 
-struct BuildTimeMapSeason {
-    lower_bound: DateTime<Utc>,
-    higher_bound: DateTime<Utc>,
-    days: BTreeMap<i64, (DateTime<Utc>, DateTime<Utc>)>,
+fn add_games(builder: &mut TimeMapBuilder, dump: GamesDump, max_season: Option<i64>) {
+    let games = match dump {
+        GamesDump::Response { data } => data,
+        GamesDump::Games(games) => games,
+    };
+
+    for game in games {
+        if max_season
+            .map(|max| game.data.season > max)
+            .unwrap_or(false)
+        {
+            continue;
+        }
+        match (game.start_time, game.end_time) {
+            (Some(start), Some(end)) => builder.add(game.data.season, game.data.day, start, end),
+            // games that never finished (or never started) don't say anything about bounds
+            _ => warn!(
+                "skipping game {} without a start and end time",
+                game.game_id.as_deref().unwrap_or("(no id)")
+            ),
+        }
+    }
 }
 
-fn main() {
-    let games: V1Games = reqwest::blocking::get(
-        "https://api.sibr.dev/chronicler/v1/games?before=2020-10-30T21:22:59.000Z",
-    )
-    .unwrap()
-    .json()
-    .unwrap();
-    let mut entries: BTreeMap<i64, BuildTimeMapSeason> = BTreeMap::new();
-    for game in games.data {
-        if !entries.contains_key(&game.data.season) {
-            entries.insert(
-                game.data.season,
-                BuildTimeMapSeason {
-                    lower_bound: game.start_time,
-                    higher_bound: game.end_time,
-                    days: BTreeMap::from([(game.data.day, (game.start_time, game.end_time))]),
-                },
-            );
-        } else {
-            let mut season = entries.get_mut(&game.data.season).unwrap();
+// the first and last event of every day's games. events whose season, day or created aren't
+// numbers can't be placed, so they're left out the same way they are from time_bounds
+fn add_events(
+    builder: &mut TimeMapBuilder,
+    db_url: &str,
+    sim: &str,
+    max_season: Option<i64>,
+) -> anyhow::Result<()> {
+    let mut db = Client::connect(db_url, NoTls)?;
+    let rows = db.query(
+        "SELECT (object->>'season')::bigint AS season, (object->>'day')::bigint AS day,
+            min((object->>'created')::bigint) AS first_created,
+            max((object->>'created')::bigint) AS last_created
+        FROM documents_millis
+        WHERE object->>'sim' = $1
+            AND jsonb_typeof(object->'gameTags') = 'array'
+            AND object->'gameTags' <> '[]'::jsonb
+            AND jsonb_typeof(object->'season') = 'number'
+            AND jsonb_typeof(object->'day') = 'number'
+            AND jsonb_typeof(object->'created') = 'number'
+            AND ($2::bigint IS NULL OR (object->>'season')::bigint <= $2)
+        GROUP BY 1, 2",
+        &[&sim, &max_season],
+    )?;
 
-            if game.start_time < season.lower_bound {
-                season.lower_bound = game.start_time;
+    for row in rows {
+        let season = row.get::<&str, Option<i64>>("season");
+        let day = row.get::<&str, Option<i64>>("day");
+        let first = row
+            .get::<&str, Option<i64>>("first_created")
+            .and_then(|t| Utc.timestamp_millis_opt(t).single());
+        let last = row
+            .get::<&str, Option<i64>>("last_created")
+            .and_then(|t| Utc.timestamp_millis_opt(t).single());
+        match (season, day, first, last) {
+            (Some(season), Some(day), Some(first), Some(last)) => {
+                builder.add(season, day, first, last)
             }
+            _ => warn!(
+                "skipping season {:?} day {:?} without usable event times",
+                season, day
+            ),
+        }
+    }
 
-            if game.end_time > season.higher_bound {
-                season.higher_bound = game.end_time;
-            }
+    Ok(())
+}
 
-            let mut day = season
-                .days
-                .entry(game.data.day)
-                .or_insert((game.start_time, game.end_time));
-            if game.start_time < day.0 {
-                day.0 = game.start_time;
-            }
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    let args = Args::parse();
 
-            if game.end_time > day.1 {
-                day.1 = game.end_time;
-            }
-        }
+    let sources = [
+        args.db_url.is_some(),
+        args.games.is_some(),
+        args.upstream.is_some(),
+    ];
+    if sources.iter().filter(|s| **s).count() != 1 {
+        anyhow::bail!("pass exactly one of --db-url, --games or --upstream");
+    }
+
+    let mut builder = TimeMapBuilder::new();
+    if let Some(url) = &args.db_url {
+        info!("reading events for {} from the database", args.sim);
+        add_events(&mut builder, url, &args.sim, args.max_season)?;
+    } else if let Some(path) = &args.games {
+        info!("reading games from {}", path.display());
+        let file = File::open(path).with_context(|| format!("couldn't open {}", path.display()))?;
+        let dump = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("{} isn't a games dump", path.display()))?;
+        add_games(&mut builder, dump, args.max_season);
+    } else if let Some(url) = &args.upstream {
+        info!("fetching games from {}", url);
+        let dump = reqwest::blocking::get(url)?
+            .error_for_status()?
+            .json()
+            .with_context(|| format!("{} didn't return games", url))?;
+        add_games(&mut builder, dump, args.max_season);
     }
 
-    let mut time_map: TimeMap = Vec::new();
+    if builder.is_empty() {
+        anyhow::bail!("found nothing to build a time map from");
+    }
 
-    for entry in entries.into_values() {
-        time_map.push(TimeMapSeason {
-            lower_bound: entry.lower_bound,
-            higher_bound: entry.higher_bound,
-            days: entry.days.into_values().collect(),
-        });
+    let time_map = builder.build()?;
+    for problem in validate_time_map(&time_map) {
+        warn!("{}", problem);
     }
+    write_time_map(&args.output, &time_map)?;
+    println!(
+        "wrote {} seasons to {}",
+        time_map.len(),
+        args.output.display()
+    );
 
-    std::fs::write("time_map.bin", bincode::serialize(&time_map).unwrap()).unwrap();
+    Ok(())
 }
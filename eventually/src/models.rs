@@ -0,0 +1,241 @@
+use chrono::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value as JSONValue};
+use std::convert::TryFrom;
+use uuid::Uuid;
+
+// the feed's RFC3339 timestamps, or the millis we store in the index
+fn parse_created(value: &JSONValue) -> Option<DateTime<Utc>> {
+    match value {
+        JSONValue::Number(n) => n
+            .as_i64()
+            .and_then(|ms| Utc.timestamp_millis_opt(ms).single()),
+        JSONValue::String(s) => s.parse::<DateTime<Utc>>().ok(),
+        _ => None,
+    }
+}
+
+// a blaseball feed event, kept exactly as it came in. the fields we use are read out of it on
+// demand and our stamps are written into it, so nothing else about it ever changes. the only
+// thing an event needs to be stored is an id
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(try_from = "JSONValue", into = "JSONValue")]
+pub struct FeedEvent {
+    pub id: Uuid,
+    raw: Map<String, JSONValue>,
+}
+
+impl TryFrom<JSONValue> for FeedEvent {
+    type Error = String;
+
+    fn try_from(value: JSONValue) -> Result<FeedEvent, String> {
+        let raw = match value {
+            JSONValue::Object(map) => map,
+            other => return Err(format!("expected an event object, got {}", other)),
+        };
+        let id = raw
+            .get("id")
+            .and_then(|i| i.as_str())
+            .and_then(|i| Uuid::parse_str(i).ok())
+            .ok_or_else(|| "event has no valid id".to_owned())?;
+        Ok(FeedEvent { id, raw })
+    }
+}
+
+impl From<FeedEvent> for JSONValue {
+    fn from(event: FeedEvent) -> JSONValue {
+        JSONValue::Object(event.raw)
+    }
+}
+
+impl FeedEvent {
+    pub fn raw(&self) -> &Map<String, JSONValue> {
+        &self.raw
+    }
+
+    pub fn created(&self) -> Option<DateTime<Utc>> {
+        self.raw.get("created").and_then(parse_created)
+    }
+
+    fn int(&self, key: &str) -> Option<i64> {
+        self.raw.get(key).and_then(|v| v.as_i64())
+    }
+
+    pub fn season(&self) -> Option<i64> {
+        self.int("season")
+    }
+
+    pub fn day(&self) -> Option<i64> {
+        self.int("day")
+    }
+
+    pub fn phase(&self) -> Option<i64> {
+        self.int("phase")
+    }
+
+    pub fn event_type(&self) -> Option<i64> {
+        self.int("type")
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.raw.get("description").and_then(|d| d.as_str())
+    }
+
+    pub fn sim(&self) -> Option<&str> {
+        self.raw.get("sim").and_then(|s| s.as_str())
+    }
+
+    pub fn game_ids(&self) -> Vec<Uuid> {
+        self.raw
+            .get("gameTags")
+            .and_then(|g| g.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|g| g.as_str())
+            .filter_map(|g| Uuid::parse_str(g).ok())
+            .collect()
+    }
+
+    // a typed copy of the metadata; changes go through `update_metadata`
+    pub fn metadata(&self) -> EventMetadata {
+        self.raw
+            .get("metadata")
+            .cloned()
+            .map(EventMetadata::from)
+            .unwrap_or_default()
+    }
+
+    // metadata that's missing (or null) starts out empty, and is only written back if `update`
+    // changed something. metadata that's something else entirely is left alone
+    pub fn update_metadata<F: FnOnce(&mut EventMetadata)>(&mut self, update: F) {
+        let mut metadata = match self.raw.get("metadata") {
+            None | Some(JSONValue::Null) => EventMetadata::default(),
+            Some(m @ JSONValue::Object(_)) => EventMetadata::from(m.clone()),
+            Some(_) => return,
+        };
+
+        let before = metadata.clone();
+        update(&mut metadata);
+        if metadata != before {
+            self.raw.insert("metadata".to_owned(), metadata.into());
+        }
+    }
+
+    // the shape events are kept in documents_millis: `created` as millis when it's a timestamp we
+    // can read, and everything else left alone
+    pub fn to_stored(&self) -> JSONValue {
+        let mut stored = self.raw.clone();
+        if let Some(created) = self.created() {
+            stored.insert("created".to_owned(), json!(created.timestamp_millis()));
+        }
+        JSONValue::Object(stored)
+    }
+
+    // the shape the feed serves events in, with `created` back as a timestamp
+    pub fn with_feed_created(mut self) -> FeedEvent {
+        if let Some(created) = self.created() {
+            self.raw.insert("created".to_owned(), json!(created));
+        }
+        self
+    }
+}
+
+// pulls `key` out of `extra` if it has the type we expect, leaving it there otherwise
+fn take_field<T: DeserializeOwned>(extra: &mut Map<String, JSONValue>, key: &str) -> Option<T> {
+    let value = extra.remove(key)?;
+    match serde_json::from_value::<T>(value.clone()) {
+        Ok(v) => Some(v),
+        Err(_) => {
+            extra.insert(key.to_owned(), value);
+            None
+        }
+    }
+}
+
+macro_rules! metadata_fields {
+    ($($field:ident: $ty:ty => $key:literal),* $(,)?) => {
+        // event metadata, with the fields we care about typed. fields that are missing or hold
+        // something unexpected stay untouched in `extra`, and metadata that isn't an object at
+        // all is kept in `unparsed`, so it round-trips exactly
+        #[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+        #[serde(from = "JSONValue", into = "JSONValue")]
+        pub struct EventMetadata {
+            $(pub $field: Option<$ty>,)*
+            pub extra: Map<String, JSONValue>,
+            pub unparsed: Option<JSONValue>,
+        }
+
+        impl From<JSONValue> for EventMetadata {
+            fn from(value: JSONValue) -> EventMetadata {
+                let mut extra = match value {
+                    JSONValue::Object(map) => map,
+                    other => {
+                        return EventMetadata {
+                            unparsed: Some(other),
+                            ..EventMetadata::default()
+                        }
+                    }
+                };
+
+                EventMetadata {
+                    $($field: take_field(&mut extra, $key),)*
+                    extra,
+                    unparsed: None,
+                }
+            }
+        }
+
+        // `unparsed` only comes back out if nothing was set alongside it
+        impl From<EventMetadata> for JSONValue {
+            fn from(metadata: EventMetadata) -> JSONValue {
+                let mut map = metadata.extra;
+                $(
+                    if let Some(v) = metadata.$field {
+                        map.insert($key.to_owned(), json!(v));
+                    }
+                )*
+                match metadata.unparsed {
+                    Some(value) if map.is_empty() => value,
+                    _ => JSONValue::Object(map),
+                }
+            }
+        }
+    };
+}
+
+metadata_fields! {
+    play: i64 => "play",
+    sub_play: i64 => "subPlay",
+    children: Vec<JSONValue> => "children",
+    parent: Uuid => "parent",
+    sibling_ids: Vec<JSONValue> => "siblingIds",
+    redacted: bool => "redacted",
+    being: i64 => "being",
+    scales: i64 => "scales",
+    ingest_source: String => "_eventually_ingest_source",
+    ingest_time: i64 => "_eventually_ingest_time",
+    book_title: String => "_eventually_book_title",
+    chapter_id: String => "_eventually_chapter_id",
+    chapter_title: String => "_eventually_chapter_title",
+}
+
+// the id lists are kept as they came so they round-trip; one bad id shouldn't hide the rest
+fn valid_ids(ids: &Option<Vec<JSONValue>>) -> Option<Vec<Uuid>> {
+    ids.as_ref().map(|ids| {
+        ids.iter()
+            .filter_map(|id| id.as_str())
+            .filter_map(|id| Uuid::parse_str(id).ok())
+            .collect()
+    })
+}
+
+impl EventMetadata {
+    pub fn child_ids(&self) -> Option<Vec<Uuid>> {
+        valid_ids(&self.children)
+    }
+
+    pub fn sibling_event_ids(&self) -> Option<Vec<Uuid>> {
+        valid_ids(&self.sibling_ids)
+    }
+}
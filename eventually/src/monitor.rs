@@ -1,11 +1,37 @@
 use chrono::prelude::*;
 use log::{debug, error, info};
 use postgres::{Client as DBClient, NoTls};
-use serde_json::{json, Value as JSONValue};
+use rustventually::sachet::bump_game_generation;
+use rustventually::{
+    parse_events, update_time_bounds_sql, upsert_event, ConflictMode, FeedEvent, Normalizer,
+    Upserted,
+};
+use serde_json::Value as JSONValue;
 use std::env;
 use std::thread;
 use std::time::{Duration, Instant};
-use uuid::Uuid;
+
+// upstream endpoints, overridable so the monitor can be pointed at a mirror (or a mock, in the tests)
+struct Upstream {
+    library: String,
+    story: String,
+    feed: String,
+}
+
+impl Upstream {
+    fn from_env() -> Upstream {
+        Upstream {
+            library: env::var("LIBRARY_URL").unwrap_or_else(|_| {
+                "https://raw.githubusercontent.com/xSke/blaseball-site-files/main/data/library.json"
+                    .to_owned()
+            }),
+            story: env::var("STORY_URL")
+                .unwrap_or_else(|_| "https://api.blaseball.com/database/feed/story".to_owned()),
+            feed: env::var("FEED_URL")
+                .unwrap_or_else(|_| "https://api.blaseball.com/database/feed/global".to_owned()),
+        }
+    }
+}
 
 macro_rules! report_error {
     ($e:expr, $where:expr) => {
@@ -15,27 +41,35 @@ macro_rules! report_error {
     };
 }
 
-fn poll_library(db: &mut DBClient, client: &reqwest::blocking::Client) -> anyhow::Result<()> {
+fn poll_library(
+    db: &mut DBClient,
+    client: &reqwest::blocking::Client,
+    upstream: &Upstream,
+) -> anyhow::Result<()> {
     let library = client
-        .get("https://raw.githubusercontent.com/xSke/blaseball-site-files/main/data/library.json")
+        .get(&upstream.library)
         .send()
         .and_then(|r| r.json::<JSONValue>())?;
     for book in library.as_array().unwrap_or(&vec![]) {
         for chapter in book["chapters"].as_array().unwrap_or(&vec![]) {
             if !chapter["redacted"].as_bool().unwrap_or(false) {
-                let events = client
-                    .get("https://api.blaseball.com/database/feed/story")
-                    .query(&vec![("id", chapter["id"].as_str())])
-                    .send()
-                    .and_then(|r| r.json::<Vec<JSONValue>>())?
-                    .into_iter()
-                    .map(|mut e| {
-                        e["metadata"]["_eventually_book_title"] = book["title"].clone();
-                        e["metadata"]["_eventually_chapter_id"] = chapter["id"].clone();
-                        e["metadata"]["_eventually_chapter_title"] = chapter["title"].clone();
-                        e
-                    })
-                    .collect::<Vec<JSONValue>>();
+                let events = parse_events(
+                    client
+                        .get(&upstream.story)
+                        .query(&vec![("id", chapter["id"].as_str())])
+                        .send()
+                        .and_then(|r| r.json::<Vec<JSONValue>>())?,
+                )
+                .into_iter()
+                .map(|mut e| {
+                    e.update_metadata(|m| {
+                        m.book_title = book["title"].as_str().map(String::from);
+                        m.chapter_id = chapter["id"].as_str().map(String::from);
+                        m.chapter_title = chapter["title"].as_str().map(String::from);
+                    });
+                    e
+                })
+                .collect::<Vec<FeedEvent>>();
                 info!(
                     "ingesting {} library events - book {}, chapter {}",
                     events.len(),
@@ -57,11 +91,13 @@ fn ingest_from_url(
     url: &str,
     parameters: Vec<(&str, String)>,
 ) -> anyhow::Result<Option<DateTime<Utc>>> {
-    let events = client
-        .get(url)
-        .query(&parameters)
-        .send()
-        .and_then(|r| r.json::<Vec<JSONValue>>())?;
+    let events = parse_events(
+        client
+            .get(url)
+            .query(&parameters)
+            .send()
+            .and_then(|r| r.json::<Vec<JSONValue>>())?,
+    );
     if events.is_empty() {
         info!("got no events from source {}", source);
         return Ok(None);
@@ -72,23 +108,29 @@ fn ingest_from_url(
         events.len(),
         source
     );
-    Ok(Some(ingest(events, db, source.to_owned())?))
+    ingest(events, db, source.to_owned())
 }
 
-fn poll_redacted(db: &mut DBClient, client: &reqwest::blocking::Client) -> anyhow::Result<()> {
+fn poll_redacted(
+    db: &mut DBClient,
+    client: &reqwest::blocking::Client,
+    upstream: &Upstream,
+) -> anyhow::Result<()> {
     let redacted_events = db.query("SELECT object FROM documents_millis WHERE object @@ '($.metadata.redacted == true) && (!exists($.metadata._eventually_book_title))'", &[])?;
 
     for redacted_e in redacted_events {
-        let redacted_e_obj = redacted_e.get::<&str, JSONValue>("object");
-        let timestamp = Utc
-            .timestamp(redacted_e_obj["created"].as_i64().unwrap(), 0)
-            .to_rfc3339();
+        let redacted_e_obj =
+            serde_json::from_value::<FeedEvent>(redacted_e.get::<&str, JSONValue>("object"))?;
+        let timestamp = match redacted_e_obj.created() {
+            Some(created) => created.to_rfc3339(),
+            None => continue,
+        };
 
         ingest_from_url(
             db,
             client,
             "blaseball.com",
-            "https://api.blaseball.com/database/feed/global",
+            &upstream.feed,
             vec![
                 ("limit", "100".to_owned()),
                 ("sort", "1".to_owned()),
@@ -114,6 +156,7 @@ fn main() {
         .build()
         .unwrap();
     let mut db = DBClient::connect(&env::var("DB_URL").unwrap(), NoTls).unwrap();
+    let upstream = Upstream::from_env();
 
     let sleep_for =
         Duration::from_millis((&env::var("POLL_DELAY").unwrap()).parse::<u64>().unwrap());
@@ -137,7 +180,7 @@ fn main() {
 
     loop {
         if last_library_fetch.elapsed() >= library_poll_delay {
-            report_error!(poll_library(&mut db, &client), "library ingest");
+            report_error!(poll_library(&mut db, &client, &upstream), "library ingest");
             last_library_fetch = Instant::now();
         }
 
@@ -155,7 +198,7 @@ fn main() {
             &mut db,
             &client,
             "blaseball.com",
-            "https://api.blaseball.com/database/feed/global",
+            &upstream.feed,
             blaseball_params,
         ) {
             Ok(time) => {
@@ -185,91 +228,53 @@ fn main() {
 }
 
 fn ingest(
-    new_events: Vec<JSONValue>,
+    new_events: Vec<FeedEvent>,
     db: &mut DBClient,
     source: String,
-) -> anyhow::Result<DateTime<Utc>> {
+) -> anyhow::Result<Option<DateTime<Utc>>> {
     let mut trans = db.transaction().unwrap(); // trans rights!
-    let latest = new_events[new_events.len() - 1]["created"]
-        .as_str()
-        .unwrap()
-        .parse::<DateTime<Utc>>()
-        .unwrap();
+    let latest = new_events.iter().filter_map(|e| e.created()).max();
 
     let mut changed_events = 0;
+    let mut stored = Vec::with_capacity(new_events.len());
 
-    for mut e in new_events {
-        e["created"] = json!(e["created"]
-            .as_str()
-            .unwrap()
-            .parse::<DateTime<Utc>>()
-            .unwrap()
-            .timestamp_millis());
+    let normalizer = Normalizer::new(&source);
 
-        e["metadata"]["_eventually_ingest_source"] = json!(&source);
+    for mut event in new_events {
+        normalizer.stamp(&mut event);
 
-        e["metadata"]["_eventually_ingest_time"] = json!(Utc::now().timestamp());
+        let id = event.id;
+        let e = event.to_stored();
 
-        let id = Uuid::parse_str(e["id"].as_str().unwrap()).unwrap();
-
-        let possible_old_event = trans.query_opt(
-            "SELECT object FROM documents_millis WHERE doc_id = $1",
-            &[&id],
-        )?;
-
-        let inserted_r = trans.query_one(
-            "INSERT INTO documents_millis (doc_id, object) VALUES ($1, $2) ON CONFLICT (doc_id) DO UPDATE SET object = $2 RETURNING (xmax=0) AS inserted",
-            &[&id, &e],
-        )?;
+        match upsert_event(&mut trans, &id, &e, ConflictMode::Version)? {
+            Upserted::Changed => {
+                debug!("Found changed event {:?}", id);
+                changed_events += 1;
 
-        if !inserted_r.get::<&str, bool>("inserted") {
-            debug!("Event {} updated; checking if changed meaningfully", id);
-            let changed_r = trans.query(
-                        "SELECT true AS existed FROM versions WHERE doc_id = $1 AND (((object::jsonb #- '{metadata,scales}') #- '{nuts}') #- '{metadata,_eventually_ingest_time}') @> ((($2::jsonb #- '{metadata,scales}') #- '{nuts}') #- '{metadata,_eventually_ingest_time}') AND (((object::jsonb #- '{metadata,scales}') #- '{nuts}') #- '{metadata,_eventually_ingest_time}') <@ ((($2::jsonb #- '{metadata,scales}') #- '{nuts}') #- '{metadata,_eventually_ingest_time}')",                    &[&id, &e]
-            ).unwrap();
+                // cached sachet packets for these games are out of date now
+                for game in event.game_ids() {
+                    bump_game_generation(&mut trans, &game)?;
+                }
+            }
+            Upserted::Inserted => {
+                trans.execute("SELECT pg_notify('new_events', $1)", &[&id.to_string()])?;
 
-            if changed_r.is_empty() {
-                debug!("Found changed event {:?}", id);
-                if let Some(old_e) = possible_old_event {
-                    let insert_statement = trans.prepare(
-                        "INSERT INTO versions (doc_id,object,observed,hash) VALUES ($1,$2,$3,
-                                                    encode(
-                                                        sha256(
-                                                            convert_to(
-                                                                ($2::jsonb #>> '{}'),
-                                                                'UTF8'
-                                                            )
-                                                        ),
-                                                    'hex')
-                                                )
-                                                RETURNING hash",
-                    )?;
-                    trans.query_one(
-                        &insert_statement,
-                        &[
-                            &id,
-                            &old_e.get::<&str, JSONValue>("object"),
-                            &(Utc::now().timestamp_millis()),
-                        ],
-                    )?;
-                    let _e_hash = trans
-                        .query_one(
-                            &insert_statement,
-                            &[&id, &e, &(Utc::now().timestamp_millis())],
-                        )?
-                        .get::<&str, String>("hash");
-                    changed_events += 1;
-                    // trans.execute("SELECT pg_notify('changed_events',$1)", &[&e_hash])?;
-                } else {
-                    trans.execute(
-                        "SELECT pg_notify('new_events',$1)",
-                        &[&e["id"].as_str().unwrap()],
-                    )?;
+                // a new event can add to a game whose packets were already cached
+                for game in event.game_ids() {
+                    bump_game_generation(&mut trans, &game)?;
                 }
             }
-        };
+            Upserted::Updated => debug!("Event {} updated without meaningful changes", id),
+            Upserted::Skipped => {}
+        }
+        stored.push(e);
     }
 
+    trans.execute(
+        update_time_bounds_sql("jsonb_array_elements($1::jsonb) AS events(object)").as_str(),
+        &[&JSONValue::Array(stored)],
+    )?;
+
     trans.commit()?;
 
     info!(
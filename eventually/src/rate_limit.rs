@@ -0,0 +1,285 @@
+use crate::*;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use rocket::http::uri::Origin;
+use rocket::http::Method;
+use rocket::{get, Data};
+
+pub const API_KEY_HEADER: &str = "X-API-Key";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    // off unless turned on, since behind a proxy every client shares the proxy's ip
+    pub enabled: bool,
+    // reject requests that don't carry an api key
+    pub require_key: bool,
+    // limits for anonymous clients, keyed by ip
+    pub requests_per_minute: u32,
+    pub burst: u32,
+    // limits for keys that don't have their own in the api_keys table
+    pub key_requests_per_minute: u32,
+    pub key_burst: u32,
+    // how long api key lookups are cached for, in seconds
+    pub key_cache_ttl: u64,
+    // most api key lookups cached at once, hits or misses; past this the least recently used go
+    pub key_cache_size: usize,
+    // most clients tracked at once; past this the ones seen least recently are forgotten
+    pub max_buckets: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: false,
+            require_key: false,
+            requests_per_minute: 120,
+            burst: 60,
+            key_requests_per_minute: 1200,
+            key_burst: 240,
+            key_cache_ttl: 60,
+            key_cache_size: 10_000,
+            max_buckets: 10_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Limit {
+    per_minute: u32,
+    burst: u32,
+}
+
+impl Limit {
+    fn per_second(&self) -> f64 {
+        self.per_minute as f64 / 60.0
+    }
+
+    fn seconds_until(&self, missing: f64) -> u64 {
+        if missing <= 0.0 || self.per_minute == 0 {
+            0
+        } else {
+            (missing / self.per_second()).ceil() as u64
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    // when the bucket was last refilled, which is also the last time its client was seen
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(limit: &Limit) -> Bucket {
+        Bucket {
+            tokens: limit.burst as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, limit: &Limit) {
+        let elapsed = self.updated.elapsed().as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second()).min(limit.burst as f64);
+        self.updated = Instant::now();
+    }
+
+    fn is_full(&self, limit: &Limit) -> bool {
+        self.tokens + self.updated.elapsed().as_secs_f64() * limit.per_second()
+            >= limit.burst as f64
+    }
+
+    fn take(&mut self, limit: &Limit) -> RateLimitOutcome {
+        self.refill(limit);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            RateLimitOutcome::Allowed {
+                limit: limit.burst,
+                remaining: self.tokens.floor() as u32,
+                reset: limit.seconds_until(limit.burst as f64 - self.tokens),
+            }
+        } else {
+            RateLimitOutcome::Limited {
+                limit: limit.burst,
+                reset: limit.seconds_until(limit.burst as f64 - self.tokens),
+                retry_after: limit.seconds_until(1.0 - self.tokens),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum RateLimitOutcome {
+    Unlimited,
+    Allowed {
+        limit: u32,
+        remaining: u32,
+        reset: u64,
+    },
+    Limited {
+        limit: u32,
+        reset: u64,
+        retry_after: u64,
+    },
+    Unauthorized,
+    // the key couldn't be checked, which isn't the client's fault
+    Unavailable,
+}
+
+// token bucket rate limiter, keyed by api key or by client ip for anonymous requests.
+// rejected requests are rerouted to the handlers below so they never reach the database.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<String, (Bucket, Limit)>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn check(&self, req: &Request<'_>) -> RateLimitOutcome {
+        let (bucket_key, limit) = match req.headers().get_one(API_KEY_HEADER) {
+            Some(key) => {
+                let found = match req.rocket().state::<ApiKeyStore>() {
+                    Some(store) => store.lookup(req.rocket(), key).await,
+                    None => Ok(None),
+                };
+
+                match found {
+                    Ok(Some(api_key)) => (
+                        format!("key:{}", api_key.key),
+                        Limit {
+                            per_minute: api_key
+                                .requests_per_minute
+                                .unwrap_or(self.config.key_requests_per_minute),
+                            burst: api_key.burst.unwrap_or(self.config.key_burst),
+                        },
+                    ),
+                    Ok(None) => return RateLimitOutcome::Unauthorized,
+                    Err(_) => return RateLimitOutcome::Unavailable,
+                }
+            }
+            None if self.config.require_key => return RateLimitOutcome::Unauthorized,
+            None => (
+                format!(
+                    "ip:{}",
+                    req.client_ip()
+                        .map(|ip| ip.to_string())
+                        .unwrap_or_else(|| "unknown".to_owned())
+                ),
+                Limit {
+                    per_minute: self.config.requests_per_minute,
+                    burst: self.config.burst,
+                },
+            ),
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if !buckets.contains_key(&bucket_key) && buckets.len() >= self.config.max_buckets {
+            evict(&mut buckets, self.config.max_buckets);
+        }
+
+        let (bucket, bucket_limit) = buckets
+            .entry(bucket_key)
+            .or_insert_with(|| (Bucket::new(&limit), limit));
+        // keys can have their limits changed while we hold a bucket for them
+        *bucket_limit = limit;
+        bucket.take(&limit)
+    }
+}
+
+// makes room for at least one more bucket. full buckets go first, since forgetting them changes
+// nothing, then the ones seen least recently, down to 90% of `max` so this doesn't run on every
+// new client
+fn evict(buckets: &mut HashMap<String, (Bucket, Limit)>, max: usize) {
+    buckets.retain(|_, (bucket, limit)| !bucket.is_full(limit));
+    if buckets.len() < max {
+        return;
+    }
+
+    let keep = max.saturating_sub(max / 10 + 1);
+    let mut seen = buckets
+        .iter()
+        .map(|(key, (bucket, _))| (bucket.updated, key.clone()))
+        .collect::<Vec<_>>();
+    seen.sort_unstable();
+    for (_, key) in seen.into_iter().take(buckets.len().saturating_sub(keep)) {
+        buckets.remove(&key);
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for RateLimiter {
+    fn info(&self) -> fairing::Info {
+        fairing::Info {
+            name: "API key rate limiting",
+            kind: fairing::Kind::Request | fairing::Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        if !self.config.enabled || req.method() == Method::Options {
+            return;
+        }
+
+        let outcome = self.check(req).await;
+        let reroute = match outcome {
+            RateLimitOutcome::Limited { .. } => Some("/_eventually/rate_limited"),
+            RateLimitOutcome::Unauthorized => Some("/_eventually/unauthorized"),
+            RateLimitOutcome::Unavailable => Some("/_eventually/unavailable"),
+            _ => None,
+        };
+
+        if let Some(path) = reroute {
+            req.set_method(Method::Get);
+            req.set_uri(Origin::parse(path).unwrap());
+        }
+
+        req.local_cache(|| outcome);
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, response: &mut Response<'r>) {
+        match *req.local_cache(|| RateLimitOutcome::Unlimited) {
+            RateLimitOutcome::Allowed {
+                limit,
+                remaining,
+                reset,
+            } => {
+                response.set_header(Header::new("X-RateLimit-Limit", limit.to_string()));
+                response.set_header(Header::new("X-RateLimit-Remaining", remaining.to_string()));
+                response.set_header(Header::new("X-RateLimit-Reset", reset.to_string()));
+            }
+            RateLimitOutcome::Limited {
+                limit,
+                reset,
+                retry_after,
+            } => {
+                response.set_header(Header::new("X-RateLimit-Limit", limit.to_string()));
+                response.set_header(Header::new("X-RateLimit-Remaining", "0"));
+                response.set_header(Header::new("X-RateLimit-Reset", reset.to_string()));
+                response.set_header(Header::new("Retry-After", retry_after.to_string()));
+            }
+            _ => {}
+        }
+    }
+}
+
+#[get("/_eventually/rate_limited")]
+pub async fn rate_limited() -> EventuallyError {
+    EventuallyError::RateLimited
+}
+
+#[get("/_eventually/unauthorized")]
+pub async fn unauthorized() -> EventuallyError {
+    EventuallyError::Unauthorized
+}
+
+#[get("/_eventually/unavailable")]
+pub async fn unavailable() -> EventuallyError {
+    EventuallyError::NoDatabase
+}
@@ -0,0 +1,518 @@
+use crate::*;
+use log::{error, info, warn};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::RwLock;
+
+// season and day bounds, indexed by season and then by day
+pub type TimeMap = Vec<TimeMapSeason>;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TimeMapSeason {
+    pub lower_bound: DateTime<Utc>,
+    pub higher_bound: DateTime<Utc>,
+    pub days: Vec<(DateTime<Utc>, DateTime<Utc>)>,
+}
+
+#[derive(Error, Debug)]
+pub enum TimeMapError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Bincode(#[from] bincode::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("no data for season {0}")]
+    MissingSeason(i64),
+    #[error("no data for season {season}, day {day}")]
+    MissingDay { season: i64, day: i64 },
+    #[error("season {0} has no days")]
+    EmptySeason(i64),
+    #[error("season {0} ends before it starts")]
+    InvertedSeason(i64),
+    #[error("season {0} starts before the previous season ends")]
+    OverlappingSeasons(i64),
+    #[error("season {season}, day {day} ends before it starts")]
+    InvertedDay { season: i64, day: i64 },
+    #[error("season {season}, day {day} starts before the previous day ends")]
+    OverlappingDays { season: i64, day: i64 },
+    #[error("season {season}, day {day} isn't within its season's bounds")]
+    DayOutsideSeason { season: i64, day: i64 },
+    #[error("{path}: {source}")]
+    File {
+        path: String,
+        source: Box<TimeMapError>,
+    },
+}
+
+// the reviewable form of a time map, for JSON and YAML files: seasons and days carry their
+// numbers instead of only their positions
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TimeMapDocument {
+    pub seasons: Vec<SeasonBounds>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SeasonBounds {
+    pub season: i64,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub days: Vec<DayBounds>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DayBounds {
+    pub day: i64,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+impl TimeMapDocument {
+    pub fn from_time_map(time_map: &[TimeMapSeason]) -> TimeMapDocument {
+        TimeMapDocument {
+            seasons: time_map
+                .iter()
+                .enumerate()
+                .map(|(season, entry)| SeasonBounds {
+                    season: season as i64,
+                    start: entry.lower_bound,
+                    end: entry.higher_bound,
+                    days: entry
+                        .days
+                        .iter()
+                        .enumerate()
+                        .map(|(day, (start, end))| DayBounds {
+                            day: day as i64,
+                            start: *start,
+                            end: *end,
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    // the numbers have to match the positions they end up at
+    pub fn into_time_map(self) -> Result<TimeMap, TimeMapError> {
+        let mut time_map = Vec::with_capacity(self.seasons.len());
+        for (expected, season) in self.seasons.into_iter().enumerate() {
+            if season.season != expected as i64 {
+                return Err(TimeMapError::MissingSeason(expected as i64));
+            }
+
+            let mut days = Vec::with_capacity(season.days.len());
+            for (expected, day) in season.days.into_iter().enumerate() {
+                if day.day != expected as i64 {
+                    return Err(TimeMapError::MissingDay {
+                        season: season.season,
+                        day: expected as i64,
+                    });
+                }
+                days.push((day.start, day.end));
+            }
+
+            time_map.push(TimeMapSeason {
+                lower_bound: season.start,
+                higher_bound: season.end,
+                days,
+            });
+        }
+        Ok(time_map)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeMapFormat {
+    Bincode,
+    Json,
+    Yaml,
+}
+
+impl TimeMapFormat {
+    // by extension; anything that isn't JSON or YAML is taken to be bincode
+    pub fn from_path(path: &Path) -> TimeMapFormat {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => TimeMapFormat::Json,
+            Some("yaml") | Some("yml") => TimeMapFormat::Yaml,
+            _ => TimeMapFormat::Bincode,
+        }
+    }
+}
+
+pub fn read_time_map(path: &Path) -> Result<TimeMap, TimeMapError> {
+    let bytes = std::fs::read(path)?;
+    match TimeMapFormat::from_path(path) {
+        TimeMapFormat::Bincode => Ok(bincode::deserialize(&bytes)?),
+        TimeMapFormat::Json => serde_json::from_slice::<TimeMapDocument>(&bytes)?.into_time_map(),
+        TimeMapFormat::Yaml => serde_yaml::from_slice::<TimeMapDocument>(&bytes)?.into_time_map(),
+    }
+}
+
+pub fn write_time_map(path: &Path, time_map: &[TimeMapSeason]) -> Result<(), TimeMapError> {
+    let bytes = match TimeMapFormat::from_path(path) {
+        TimeMapFormat::Bincode => bincode::serialize(time_map)?,
+        TimeMapFormat::Json => {
+            let mut bytes = serde_json::to_vec_pretty(&TimeMapDocument::from_time_map(time_map))?;
+            bytes.push(b'\n');
+            bytes
+        }
+        TimeMapFormat::Yaml => serde_yaml::to_vec(&TimeMapDocument::from_time_map(time_map))?,
+    };
+    std::fs::write(path, bytes)?;
+    Ok(())
+}
+
+// every problem with a time map, rather than only the first
+pub fn validate_time_map(time_map: &[TimeMapSeason]) -> Vec<TimeMapError> {
+    let mut problems = Vec::new();
+    for (season, entry) in time_map.iter().enumerate() {
+        let season = season as i64;
+        if entry.days.is_empty() {
+            problems.push(TimeMapError::EmptySeason(season));
+        }
+        if entry.higher_bound < entry.lower_bound {
+            problems.push(TimeMapError::InvertedSeason(season));
+        }
+        if season > 0 && entry.lower_bound < time_map[season as usize - 1].higher_bound {
+            problems.push(TimeMapError::OverlappingSeasons(season));
+        }
+
+        for (day, (start, end)) in entry.days.iter().enumerate() {
+            let day = day as i64;
+            if end < start {
+                problems.push(TimeMapError::InvertedDay { season, day });
+            }
+            if day > 0 && *start < entry.days[day as usize - 1].1 {
+                problems.push(TimeMapError::OverlappingDays { season, day });
+            }
+            if *start < entry.lower_bound || *end > entry.higher_bound {
+                problems.push(TimeMapError::DayOutsideSeason { season, day });
+            }
+        }
+    }
+    problems
+}
+
+struct BuildSeason {
+    lower_bound: DateTime<Utc>,
+    higher_bound: DateTime<Utc>,
+    days: BTreeMap<i64, (DateTime<Utc>, DateTime<Utc>)>,
+}
+
+// widens season and day bounds to cover every span it's given, be it a game or an event
+#[derive(Default)]
+pub struct TimeMapBuilder {
+    seasons: BTreeMap<i64, BuildSeason>,
+}
+
+impl TimeMapBuilder {
+    pub fn new() -> TimeMapBuilder {
+        TimeMapBuilder::default()
+    }
+
+    pub fn add(&mut self, season: i64, day: i64, start: DateTime<Utc>, end: DateTime<Utc>) {
+        let entry = self.seasons.entry(season).or_insert(BuildSeason {
+            lower_bound: start,
+            higher_bound: end,
+            days: BTreeMap::new(),
+        });
+        entry.lower_bound = entry.lower_bound.min(start);
+        entry.higher_bound = entry.higher_bound.max(end);
+
+        let bounds = entry.days.entry(day).or_insert((start, end));
+        bounds.0 = bounds.0.min(start);
+        bounds.1 = bounds.1.max(end);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seasons.is_empty()
+    }
+
+    // seasons and days are looked up by position, so both have to run from 0 without gaps
+    pub fn build(self) -> Result<TimeMap, TimeMapError> {
+        let seasons = self.build_seasons()?;
+        let mut time_map = Vec::with_capacity(seasons.len());
+        for (expected, (season, entry)) in seasons.into_iter().enumerate() {
+            if season != expected as i64 {
+                return Err(TimeMapError::MissingSeason(expected as i64));
+            }
+            time_map.push(entry);
+        }
+        Ok(time_map)
+    }
+
+    // each season on its own, for merging into another time map. only days have to run from 0
+    pub fn build_seasons(self) -> Result<BTreeMap<i64, TimeMapSeason>, TimeMapError> {
+        let mut seasons = BTreeMap::new();
+        for (season, entry) in self.seasons {
+            let mut days = Vec::with_capacity(entry.days.len());
+            for (expected, (day, bounds)) in entry.days.into_iter().enumerate() {
+                if day != expected as i64 {
+                    return Err(TimeMapError::MissingDay {
+                        season,
+                        day: expected as i64,
+                    });
+                }
+                days.push(bounds);
+            }
+
+            seasons.insert(
+                season,
+                TimeMapSeason {
+                    lower_bound: entry.lower_bound,
+                    higher_bound: entry.higher_bound,
+                    days,
+                },
+            );
+        }
+        Ok(seasons)
+    }
+}
+
+impl TimeMapSeason {
+    pub fn bounds(&self, day: Option<i64>) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        match day {
+            Some(d) if d < 0 => None,
+            Some(d) => self.days.get(d as usize).copied(),
+            None => Some((self.lower_bound, self.higher_bound)),
+        }
+    }
+}
+
+// the season and day `at` falls in (or, between games, the last one to start before it), if it's
+// within the time map at all
+pub fn locate_in_time_map(
+    time_map: &[TimeMapSeason],
+    at: DateTime<Utc>,
+) -> Option<(i64, i64, bool)> {
+    if at < time_map.first()?.lower_bound || at > time_map.last()?.higher_bound {
+        return None;
+    }
+
+    let season = time_map.iter().rposition(|s| s.lower_bound <= at)?;
+    let entry = &time_map[season];
+    let day = entry.days.iter().rposition(|d| d.0 <= at)?;
+    Some((season as i64, day as i64, at > entry.days[day].1))
+}
+
+// the sim blaseball itself ran on
+pub const MAIN_SIM: &str = "thisidisstaticyo";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TimeMapConfig {
+    // time map files (bincode, JSON or YAML), by the sim they cover. these are historical, so
+    // answers from them are cached as immutable. relative paths are relative to the config file
+    // they're set in, or to the working directory for the default. the server won't start if
+    // one of them can't be loaded
+    pub files: HashMap<String, String>,
+    // also load the time_map table, whose seasons take precedence over the same seasons in files
+    pub table: bool,
+}
+
+impl Default for TimeMapConfig {
+    fn default() -> Self {
+        TimeMapConfig {
+            files: HashMap::from([(MAIN_SIM.to_owned(), "time_map.json".to_owned())]),
+            table: true,
+        }
+    }
+}
+
+impl TimeMapConfig {
+    pub fn resolve_paths(&mut self, base: &Path) {
+        for path in self.files.values_mut() {
+            if Path::new(path).is_relative() {
+                *path = base.join(&path).to_string_lossy().into_owned();
+            }
+        }
+    }
+}
+
+pub struct TimeMapPosition {
+    pub sim: String,
+    pub season: i64,
+    pub day: i64,
+    pub between_games: bool,
+    pub settled: bool,
+}
+
+struct LoadedTimeMap {
+    time_map: TimeMap,
+    // by season, whether it came from a file, and so won't change
+    from_file: Vec<bool>,
+}
+
+impl LoadedTimeMap {
+    // table seasons replace the file's, or carry on right after them. nothing changes unless
+    // all of them fit
+    fn merge(&mut self, seasons: BTreeMap<i64, TimeMapSeason>) -> Result<(), TimeMapError> {
+        let mut next = self.time_map.len() as i64;
+        for &season in seasons.keys() {
+            if season < 0 || season > next {
+                return Err(TimeMapError::MissingSeason(next));
+            } else if season == next {
+                next += 1;
+            }
+        }
+
+        for (season, entry) in seasons {
+            let index = season as usize;
+            if index == self.time_map.len() {
+                self.time_map.push(entry);
+                self.from_file.push(false);
+            } else {
+                self.time_map[index] = entry;
+                self.from_file[index] = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+// time maps for every sim we have precomputed bounds for, reloadable while the server is up
+pub struct TimeMapStore {
+    config: TimeMapConfig,
+    maps: RwLock<HashMap<String, LoadedTimeMap>>,
+}
+
+impl TimeMapStore {
+    // only reads the files; the table needs a database connection, see `refresh`
+    pub fn new(config: TimeMapConfig) -> Result<TimeMapStore, TimeMapError> {
+        let store = TimeMapStore {
+            config,
+            maps: RwLock::new(HashMap::new()),
+        };
+        *store.maps.write().unwrap() = store.load_files()?;
+        Ok(store)
+    }
+
+    fn load_files(&self) -> Result<HashMap<String, LoadedTimeMap>, TimeMapError> {
+        let mut maps = HashMap::new();
+        for (sim, path) in &self.config.files {
+            let time_map = read_time_map(Path::new(path)).map_err(|e| TimeMapError::File {
+                path: path.clone(),
+                source: Box::new(e),
+            })?;
+            info!("loaded time map for {} from {}", sim, path);
+            for problem in validate_time_map(&time_map) {
+                warn!("time map for {} from {}: {}", sim, path, problem);
+            }
+            maps.insert(
+                sim.clone(),
+                LoadedTimeMap {
+                    from_file: vec![true; time_map.len()],
+                    time_map,
+                },
+            );
+        }
+        Ok(maps)
+    }
+
+    // reloads the files and the time_map table. nothing changes if either can't be read
+    pub async fn refresh(&self, db: &CompassConn) -> Result<(), EventuallyError> {
+        let mut maps = self.load_files()?;
+
+        if self.config.table {
+            let rows = db
+                .run(|c| {
+                    c.query(
+                        "SELECT sim, season, day, start_time, end_time FROM time_map",
+                        &[],
+                    )
+                })
+                .await?;
+
+            let mut builders: HashMap<String, TimeMapBuilder> = HashMap::new();
+            for row in rows {
+                let sim: String = row.get("sim");
+                let (season, day): (i64, i64) = (row.get("season"), row.get("day"));
+                let start = Utc.timestamp_millis_opt(row.get("start_time")).single();
+                let end = Utc.timestamp_millis_opt(row.get("end_time")).single();
+                match (start, end) {
+                    (Some(start), Some(end)) => {
+                        builders.entry(sim).or_default().add(season, day, start, end)
+                    }
+                    _ => warn!(
+                        "skipping the time_map table's entry for {} season {} day {}, its times are out of range",
+                        sim, season, day
+                    ),
+                }
+            }
+
+            for (sim, builder) in builders {
+                let mut merged = maps.remove(&sim).unwrap_or(LoadedTimeMap {
+                    time_map: Vec::new(),
+                    from_file: Vec::new(),
+                });
+                if let Err(e) = builder
+                    .build_seasons()
+                    .and_then(|seasons| merged.merge(seasons))
+                {
+                    error!("ignoring the time_map table's entries for {}: {}", sim, e);
+                }
+                if merged.time_map.is_empty() {
+                    continue;
+                }
+                maps.insert(sim, merged);
+            }
+        }
+
+        *self.maps.write().unwrap() = maps;
+        Ok(())
+    }
+
+    // the start and end of a season (or one of its days), and whether that's settled for good
+    pub fn bounds(
+        &self,
+        sim: &str,
+        season: i64,
+        day: Option<i64>,
+    ) -> Option<(DateTime<Utc>, DateTime<Utc>, bool)> {
+        if season < 0 {
+            return None;
+        }
+
+        let maps = self.maps.read().unwrap();
+        let loaded = maps.get(sim)?;
+        let (start, end) = loaded.time_map.get(season as usize)?.bounds(day)?;
+        Some((start, end, loaded.from_file[season as usize]))
+    }
+
+    // where `at` falls in the time map of `sim`, or of any sim covering it
+    pub fn locate(&self, sim: Option<&str>, at: DateTime<Utc>) -> Option<TimeMapPosition> {
+        let maps = self.maps.read().unwrap();
+        // without a sim, the main one wins, then the rest by name, so overlapping maps always
+        // give the same answer
+        let mut candidates = maps
+            .iter()
+            .filter(|(s, _)| sim.map(|sim| sim == s.as_str()).unwrap_or(true))
+            .collect::<Vec<_>>();
+        candidates.sort_by(|(a, _), (b, _)| {
+            (a.as_str() != MAIN_SIM, a.as_str()).cmp(&(b.as_str() != MAIN_SIM, b.as_str()))
+        });
+        candidates.into_iter().find_map(|(s, loaded)| {
+            locate_in_time_map(&loaded.time_map, at).map(|(season, day, between_games)| {
+                TimeMapPosition {
+                    sim: s.clone(),
+                    season,
+                    day,
+                    between_games,
+                    settled: loaded.from_file[season as usize],
+                }
+            })
+        })
+    }
+
+    // how many seasons each sim has
+    pub fn summary(&self) -> HashMap<String, usize> {
+        self.maps
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(sim, loaded)| (sim.clone(), loaded.time_map.len()))
+            .collect()
+    }
+}
@@ -0,0 +1,51 @@
+use clap::Parser;
+use rustventually::{read_time_map, validate_time_map, write_time_map};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[clap(name = "time_map", about = "converts and checks time map files")]
+enum Command {
+    /// converts between bincode, JSON (.json) and YAML (.yaml, .yml), going by the extensions
+    Convert { input: PathBuf, output: PathBuf },
+    /// checks that bounds are in order, days don't overlap, and days fall within their season
+    Validate {
+        #[clap(required = true)]
+        inputs: Vec<PathBuf>,
+    },
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+
+    match Command::parse() {
+        Command::Convert { input, output } => {
+            let time_map = read_time_map(&input)?;
+            for problem in validate_time_map(&time_map) {
+                eprintln!("warning: {}: {}", input.display(), problem);
+            }
+            write_time_map(&output, &time_map)?;
+            println!("wrote {} seasons to {}", time_map.len(), output.display());
+        }
+        Command::Validate { inputs } => {
+            let mut failed = false;
+            for input in inputs {
+                let problems = match read_time_map(&input) {
+                    Ok(time_map) => validate_time_map(&time_map),
+                    Err(e) => vec![e],
+                };
+                if problems.is_empty() {
+                    println!("{}: ok", input.display());
+                }
+                for problem in problems {
+                    println!("{}: {}", input.display(), problem);
+                    failed = true;
+                }
+            }
+            if failed {
+                std::process::exit(1);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,231 @@
+#[macro_use]
+mod common;
+
+use common::*;
+
+const GAME: &str = "8f6a2c1e-0b5d-4c1f-9a3e-2d7b6c5a4f10";
+const INCINERATION: &str = "33333333-3333-4333-8333-333333333333";
+const REPLACEMENT: &str = "44444444-4444-4444-8444-444444444444";
+
+#[test]
+fn search_and_count() {
+    let db = test_db!();
+    db.fill("events.ndjson");
+
+    runtime().block_on(async {
+        let client = local_client(db.figment()).await;
+
+        let count = get_json(&client, "/count?season=11").await;
+        assert_eq!(count["count"], 5);
+
+        let count = get_json(&client, &format!("/count?gameTags={}", GAME)).await;
+        assert_eq!(count["count"], 4);
+
+        let events = get_json(&client, &format!("/events?gameTags={}&sortorder=asc", GAME)).await;
+        let descriptions: Vec<&str> = events
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["description"].as_str().unwrap())
+            .collect();
+        assert_eq!(descriptions.len(), 4);
+        assert_eq!(descriptions[0], "Top of 1, Jessica Telephone batting.");
+
+        let events = get_json(&client, "/events?description=incinerated").await;
+        assert_eq!(events.as_array().unwrap().len(), 1);
+        assert_eq!(events[0]["id"], INCINERATION);
+
+        let events = get_json(&client, "/events?limit=2&offset=1&sortorder=asc").await;
+        assert_eq!(events.as_array().unwrap().len(), 2);
+        assert_eq!(events[0]["description"], "Jessica Telephone hits a Single!");
+    });
+}
+
+#[test]
+fn search_expands_children_and_parents() {
+    let db = test_db!();
+    db.fill("events.ndjson");
+
+    runtime().block_on(async {
+        let client = local_client(db.figment()).await;
+
+        // the incineration's children also hold an id that isn't one, which is skipped
+        let events = get_json(&client, "/events?type=54&expand_children=true").await;
+        let children = events[0]["metadata"]["children"].as_array().unwrap();
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0]["id"], REPLACEMENT);
+
+        let events = get_json(&client, "/events?type=51&expand_parent=true").await;
+        assert_eq!(events[0]["metadata"]["parent"]["id"], INCINERATION);
+    });
+}
+
+#[test]
+fn time_map() {
+    let db = test_db!();
+    db.fill("events.ndjson");
+
+    runtime().block_on(async {
+        let client = local_client(db.figment()).await;
+
+        // seasons up to 10 come from time_map.json, and never change
+        let response = client.get("/time/thisidisstaticyo/0").dispatch().await;
+        assert!(response
+            .headers()
+            .get_one("Cache-Control")
+            .unwrap()
+            .contains("immutable"));
+        let time = response.into_json::<serde_json::Value>().await.unwrap();
+        assert!(time["start"].is_string() && time["end"].is_string());
+
+        let time = get_json(&client, "/time/thisidisstaticyo/11/5").await;
+        let start = time["start"]
+            .as_str()
+            .unwrap()
+            .parse::<chrono::DateTime<chrono::Utc>>()
+            .unwrap();
+        let end = time["end"]
+            .as_str()
+            .unwrap()
+            .parse::<chrono::DateTime<chrono::Utc>>()
+            .unwrap();
+        assert_eq!(start.timestamp_millis(), 1615834800000);
+        assert_eq!(end.timestamp_millis(), 1615834810000);
+    });
+}
+
+#[test]
+fn time_at() {
+    let db = test_db!();
+    db.fill("events.ndjson");
+
+    runtime().block_on(async {
+        let client = local_client(db.figment()).await;
+
+        // early seasons come from time_map.json
+        let season = get_json(&client, "/time/thisidisstaticyo/0").await;
+        let at = get_json(
+            &client,
+            &format!("/time/at?timestamp={}", season["start"].as_str().unwrap()),
+        )
+        .await;
+        assert_eq!(at["sim"], "thisidisstaticyo");
+        assert_eq!(at["season"], 0);
+        assert_eq!(at["day"], 0);
+        assert_eq!(at["betweenGames"], false);
+
+        // later ones from the index
+        let at = get_json(&client, "/time/at?timestamp=1615834805000").await;
+        assert_eq!(at["season"], 11);
+        assert_eq!(at["day"], 5);
+        assert_eq!(at["phase"], 2);
+        assert_eq!(at["betweenGames"], false);
+
+        let at = get_json(
+            &client,
+            "/time/at?timestamp=2021-03-15T20:00:00Z&sim=thisidisstaticyo",
+        )
+        .await;
+        assert_eq!(at["day"], 5);
+        assert_eq!(at["betweenGames"], true);
+
+        let response = client.get("/time/at?timestamp=yesterday").dispatch().await;
+        assert_eq!(response.status(), rocket::http::Status::BadRequest);
+    });
+}
+
+#[test]
+fn time_map_table_refresh() {
+    let db = test_db!();
+    db.connect()
+        .batch_execute(
+            "INSERT INTO api_keys (key, name, admin) VALUES ('admin-key', 'tests', true);
+            INSERT INTO time_map (sim, season, day, start_time, end_time) VALUES
+                ('gamma', 0, 0, 1650000000000, 1650003600000),
+                ('gamma', 0, 1, 1650007200000, 1650010800000),
+                ('thisidisstaticyo', 11, 0, 1615831200000, 1615834800000);",
+        )
+        .unwrap();
+
+    runtime().block_on(async {
+        let client = local_client(db.figment()).await;
+
+        let summary = client
+            .post("/admin/time_map/refresh")
+            .header(rocket::http::Header::new("X-API-Key", "admin-key"))
+            .dispatch()
+            .await
+            .into_json::<serde_json::Value>()
+            .await
+            .unwrap();
+        assert_eq!(summary["gamma"], 1);
+        // the table's season carries on after the file's
+        assert_eq!(summary["thisidisstaticyo"], 12);
+
+        let time = get_json(&client, "/time/gamma/0/1").await;
+        assert_eq!(time["start"], "2022-04-15T07:20:00Z");
+        assert_eq!(time["end"], "2022-04-15T08:20:00Z");
+
+        let time = get_json(&client, "/time/gamma/0").await;
+        assert_eq!(time["start"], "2022-04-15T05:20:00Z");
+
+        let time = get_json(&client, "/time/thisidisstaticyo/11/0").await;
+        assert_eq!(time["start"], "2021-03-15T18:00:00Z");
+        // the file's seasons are still there, and still settled
+        let response = client.get("/time/thisidisstaticyo/0").dispatch().await;
+        assert!(response
+            .headers()
+            .get_one("Cache-Control")
+            .unwrap()
+            .contains("immutable"));
+
+        let response = client.post("/admin/time_map/refresh").dispatch().await;
+        assert_eq!(response.status(), rocket::http::Status::Unauthorized);
+    });
+}
+
+#[test]
+fn sachet_packets() {
+    let db = test_db!();
+    db.fill("events.ndjson");
+
+    let rt = runtime();
+    let mock = launch_mock(&rt);
+
+    rt.block_on(async {
+        let figment = db.figment().merge((
+            "sachet.chronicler_url",
+            mock.url("/chronicler/games/updates"),
+        ));
+        let client = local_client(figment).await;
+
+        let packets = get_json(&client, &format!("/sachet/packets?id={}", GAME)).await;
+        let packets = packets.as_array().unwrap();
+        assert_eq!(packets.len(), 4);
+        assert!(packets
+            .iter()
+            .all(|p| p["_sachet_packet_incomplete"] == false));
+        assert_eq!(packets[0]["playCount"], 0);
+        assert_eq!(packets[3]["playCount"], 2);
+        assert_eq!(packets[3]["subPlay"], 1);
+        assert_eq!(packets[3]["gameId"], GAME);
+
+        let packets = get_json(
+            &client,
+            &format!("/sachet/packets?id={}&from_play=1&to_play=1", GAME),
+        )
+        .await;
+        assert_eq!(packets.as_array().unwrap().len(), 1);
+
+        // finished games are cached by now, but can still change, so clients have to revalidate
+        let response = client
+            .get(format!("/sachet/packets?id={}", GAME))
+            .dispatch()
+            .await;
+        assert!(!response
+            .headers()
+            .get_one("Cache-Control")
+            .unwrap()
+            .contains("immutable"));
+    });
+}
@@ -0,0 +1,206 @@
+#[macro_use]
+mod common;
+
+use common::*;
+use rustventually::{ArchiveManifest, MANIFEST_FILE};
+use std::fs;
+use std::process::{Command, Stdio};
+use uuid::Uuid;
+
+const CHANGED: &str = "55555555-5555-4555-8555-555555555555";
+const LATE: &str = "88888888-8888-4888-8888-888888888888";
+
+fn export(db: &TestDb, output: &std::path::Path, extra: &[&str]) {
+    let status = Command::new(env!("CARGO_BIN_EXE_export"))
+        .arg("--output")
+        .arg(output)
+        .arg("--db-url")
+        .arg(&db.url)
+        .arg("--chunk-size")
+        .arg("2")
+        .args(extra)
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success(), "export failed");
+}
+
+fn fill(db: &TestDb, input: &std::path::Path) {
+    let status = Command::new(env!("CARGO_BIN_EXE_fill"))
+        .arg("--input")
+        .arg(input)
+        .arg("--db-url")
+        .arg(&db.url)
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success(), "fill failed on {}", input.display());
+}
+
+fn counts(db: &TestDb) -> (i64, i64) {
+    let mut conn = db.connect();
+    let documents = conn
+        .query_one("SELECT count(*) FROM documents_millis", &[])
+        .unwrap()
+        .get(0);
+    let versions = conn
+        .query_one("SELECT count(*) FROM versions", &[])
+        .unwrap()
+        .get(0);
+    (documents, versions)
+}
+
+type DocumentRow = (Uuid, String);
+type VersionRow = (Uuid, String, Option<i64>, Option<String>);
+
+// every row of documents_millis and versions, as text, in a fixed order
+fn contents(db: &TestDb) -> (Vec<DocumentRow>, Vec<VersionRow>) {
+    let mut conn = db.connect();
+    let documents = conn
+        .query(
+            "SELECT doc_id, object::text FROM documents_millis ORDER BY doc_id",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect();
+    let versions = conn
+        .query(
+            "SELECT doc_id, object::text, observed, hash FROM versions
+                ORDER BY doc_id, observed, hash, object::text",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| (row.get(0), row.get(1), row.get(2), row.get(3)))
+        .collect();
+    (documents, versions)
+}
+
+#[test]
+fn export_round_trips_through_fill() {
+    let source = test_db!();
+    source.fill("events.ndjson");
+    source
+        .connect()
+        .execute(
+            "INSERT INTO versions (doc_id, object, observed, hash)
+                SELECT doc_id, object, 1615924860000, 'abc' FROM documents_millis WHERE doc_id = $1",
+            &[&Uuid::parse_str(CHANGED).unwrap()],
+        )
+        .unwrap();
+
+    let dir = std::env::temp_dir().join(format!("eventually-export-{}", Uuid::new_v4()));
+    export(&source, &dir, &[]);
+
+    let manifest = ArchiveManifest::read(&dir).unwrap();
+    assert_eq!(manifest.documents.count, 5);
+    assert_eq!(manifest.documents.chunks.len(), 3);
+    assert_eq!(manifest.versions.count, 1);
+
+    let target = TestDb::create().unwrap();
+    // loading twice shouldn't duplicate anything
+    for _ in 0..2 {
+        fill(&target, &dir.join(MANIFEST_FILE));
+    }
+    assert_eq!(counts(&target), counts(&source));
+    assert_eq!(contents(&target), contents(&source));
+
+    // nothing's been ingested since the last ingest time, so an incremental export from the
+    // second after it is empty
+    let last_ingest = source
+        .connect()
+        .query_one(
+            "SELECT max((object #>> '{metadata,_eventually_ingest_time}')::bigint) FROM documents_millis",
+            &[],
+        )
+        .unwrap()
+        .get::<_, i64>(0);
+    let next = std::env::temp_dir().join(format!("eventually-export-{}", Uuid::new_v4()));
+    export(&source, &next, &["--since", &(last_ingest + 1).to_string()]);
+    let manifest = ArchiveManifest::read(&next).unwrap();
+    assert_eq!(manifest.documents.count, 0);
+    assert_eq!(manifest.versions.count, 0);
+
+    // a damaged chunk is refused before anything is written
+    let chunk = dir.join(&ArchiveManifest::read(&dir).unwrap().documents.chunks[0].file);
+    fs::write(&chunk, b"not zstd").unwrap();
+    let fresh = TestDb::create().unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_fill"))
+        .arg("--input")
+        .arg(&dir)
+        .arg("--db-url")
+        .arg(&fresh.url)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+    assert_eq!(counts(&fresh), (0, 0));
+
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&next);
+}
+
+#[test]
+fn incremental_export_catches_fills_running_across_it() {
+    let source = test_db!();
+    source.fill("events.ndjson");
+
+    // fill stamps everything with the time it started, so a fill that started an hour ago is
+    // still committing events stamped then, after the first export has been taken
+    let started = chrono::Utc::now().timestamp() - 3600;
+    source
+        .connect()
+        .execute(
+            "UPDATE documents_millis
+                SET object = jsonb_set(object, '{metadata,_eventually_ingest_time}', to_jsonb($1::bigint))",
+            &[&started],
+        )
+        .unwrap();
+
+    let first = std::env::temp_dir().join(format!("eventually-export-{}", Uuid::new_v4()));
+    export(&source, &first, &[]);
+    assert_eq!(ArchiveManifest::read(&first).unwrap().documents.count, 5);
+
+    let late = std::env::temp_dir().join(format!("eventually-late-{}.ndjson", Uuid::new_v4()));
+    fs::write(
+        &late,
+        serde_json::json!({
+            "id": LATE,
+            "created": 1615835000000i64,
+            "season": 11,
+            "day": 5,
+            "type": 0,
+            "description": "Committed after the first export.",
+            "sim": "thisidisstaticyo",
+            "gameTags": [],
+            "metadata": { "_eventually_ingest_time": started }
+        })
+        .to_string(),
+    )
+    .unwrap();
+    fill(&source, &late);
+
+    let second = std::env::temp_dir().join(format!("eventually-export-{}", Uuid::new_v4()));
+    export(
+        &source,
+        &second,
+        &[
+            "--since-manifest",
+            first.join(MANIFEST_FILE).to_str().unwrap(),
+        ],
+    );
+    assert_eq!(ArchiveManifest::read(&second).unwrap().documents.count, 6);
+
+    // the two archives together hold everything
+    let target = TestDb::create().unwrap();
+    fill(&target, &first);
+    fill(&target, &second);
+    assert_eq!(contents(&target), contents(&source));
+
+    let _ = fs::remove_dir_all(&first);
+    let _ = fs::remove_dir_all(&second);
+    let _ = fs::remove_file(&late);
+}
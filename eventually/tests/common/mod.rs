@@ -0,0 +1,312 @@
+// shared setup for the integration tests: a throwaway postgres, fixture loading through the fill
+// binary, the server mounted on a local client, and a mock upstream for the monitor and chronicler.
+//
+// postgres comes from EVENTUALLY_TEST_DATABASE_URL (any database on a server we may create
+// databases on), or from a temporary cluster if initdb and pg_ctl are on the PATH. with neither,
+// the tests print a note and pass without doing anything, except under CI (when CI is set), where
+// that fails them instead.
+#![allow(dead_code)]
+
+use postgres::{Client as DBClient, NoTls};
+use rocket::figment::Figment;
+use rocket::local::asynchronous::Client;
+use rocket::serde::json::Json;
+use rocket::tokio::runtime::Runtime;
+use rocket::{get, routes, State};
+use serde_json::{json, Value as JSONValue};
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+pub const TEST_DATABASE_ENV: &str = "EVENTUALLY_TEST_DATABASE_URL";
+
+pub fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+pub fn read_fixture(name: &str) -> JSONValue {
+    serde_json::from_slice(&fs::read(fixture(name)).unwrap()).unwrap()
+}
+
+pub fn runtime() -> Runtime {
+    Runtime::new().unwrap()
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn wait_for_port(port: u16) {
+    let started = Instant::now();
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(
+            started.elapsed() < Duration::from_secs(30),
+            "nothing came up on port {}",
+            port
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn with_database(url: &str, name: &str) -> String {
+    let (base, _) = url.split('?').next().unwrap().rsplit_once('/').unwrap();
+    format!("{}/{}", base, name)
+}
+
+// a postgres cluster of our own, for when there's no server to borrow
+struct TempCluster {
+    dir: PathBuf,
+    url: String,
+}
+
+impl TempCluster {
+    fn start() -> Option<TempCluster> {
+        let dir = std::env::temp_dir().join(format!("eventually-pg-{}", Uuid::new_v4()));
+        let data = dir.join("data");
+
+        let initialized = Command::new("initdb")
+            .arg("-D")
+            .arg(&data)
+            .args(&["-U", "postgres", "--auth=trust"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map(|s| s.success())
+            .unwrap_or(false);
+        if !initialized {
+            let _ = fs::remove_dir_all(&dir);
+            return None;
+        }
+
+        let port = free_port();
+        let started = Command::new("pg_ctl")
+            .arg("-D")
+            .arg(&data)
+            .arg("-l")
+            .arg(dir.join("postgres.log"))
+            .arg("-o")
+            .arg(format!("-p {} -k {} -h 127.0.0.1", port, dir.display()))
+            .args(&["-w", "start"])
+            .stdout(Stdio::null())
+            .status()
+            .map(|s| s.success())
+            .unwrap_or(false);
+
+        let cluster = TempCluster {
+            url: format!("postgres://postgres@127.0.0.1:{}/postgres", port),
+            dir,
+        };
+        if started {
+            Some(cluster)
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for TempCluster {
+    fn drop(&mut self) {
+        let _ = Command::new("pg_ctl")
+            .arg("-D")
+            .arg(self.dir.join("data"))
+            .args(&["-m", "immediate", "stop"])
+            .stdout(Stdio::null())
+            .status();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+// the test's database, returning from the test early when there's no postgres to run it against
+macro_rules! test_db {
+    () => {
+        match $crate::common::TestDb::create() {
+            Some(db) => db,
+            None => return,
+        }
+    };
+}
+
+// a database with db/schema.sql applied, dropped again once the test is done
+pub struct TestDb {
+    pub url: String,
+    admin_url: String,
+    name: String,
+    // declared last so the database is dropped before its cluster goes away
+    _cluster: Option<TempCluster>,
+}
+
+impl TestDb {
+    pub fn create() -> Option<TestDb> {
+        let (admin_url, cluster) = match std::env::var(TEST_DATABASE_ENV) {
+            Ok(url) => (url, None),
+            Err(_) => match TempCluster::start() {
+                Some(cluster) => (cluster.url.clone(), Some(cluster)),
+                None => {
+                    // a skipped test would look like a passing one in CI
+                    if std::env::var_os("CI").is_some() {
+                        panic!("no postgres to test against; set {}", TEST_DATABASE_ENV);
+                    }
+                    eprintln!(
+                        "skipping: set {} or put initdb and pg_ctl on the PATH to run this test",
+                        TEST_DATABASE_ENV
+                    );
+                    return None;
+                }
+            },
+        };
+
+        let name = format!("eventually_test_{}", Uuid::new_v4().to_simple());
+        let mut admin = DBClient::connect(&admin_url, NoTls).unwrap();
+        admin
+            .batch_execute(&format!("CREATE DATABASE {}", name))
+            .unwrap();
+
+        let url = with_database(&admin_url, &name);
+        let schema =
+            fs::read_to_string(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../db/schema.sql"))
+                .unwrap();
+        DBClient::connect(&url, NoTls)
+            .unwrap()
+            .batch_execute(&schema)
+            .unwrap();
+
+        Some(TestDb {
+            url,
+            admin_url,
+            name,
+            _cluster: cluster,
+        })
+    }
+
+    pub fn connect(&self) -> DBClient {
+        DBClient::connect(&self.url, NoTls).unwrap()
+    }
+
+    // loads a fixture the same way dumps are loaded in production
+    pub fn fill(&self, name: &str) {
+        let status = Command::new(env!("CARGO_BIN_EXE_fill"))
+            .arg("--input")
+            .arg(fixture(name))
+            .arg("--db-url")
+            .arg(&self.url)
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success(), "fill failed on {}", name);
+    }
+
+    pub fn figment(&self) -> Figment {
+        rocket::Config::figment()
+            .merge(("databases.eventually.url", &self.url))
+            .merge(("cache_temporary", true))
+            .merge(("rate_limit.enabled", false))
+            .merge(("compression.enabled", false))
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        if let Ok(mut admin) = DBClient::connect(&self.admin_url, NoTls) {
+            let _ = admin.execute(
+                "SELECT pg_terminate_backend(pid) FROM pg_stat_activity WHERE datname = $1",
+                &[&self.name],
+            );
+            let _ = admin.batch_execute(&format!("DROP DATABASE IF EXISTS {}", self.name));
+        }
+    }
+}
+
+pub async fn local_client(figment: Figment) -> Client {
+    Client::tracked(rustventually::build_rocket(figment))
+        .await
+        .unwrap()
+}
+
+pub async fn get_json(client: &Client, uri: &str) -> JSONValue {
+    let response = client.get(uri.to_owned()).dispatch().await;
+    assert_eq!(
+        response.status(),
+        rocket::http::Status::Ok,
+        "GET {} failed",
+        uri
+    );
+    response.into_json::<JSONValue>().await.unwrap()
+}
+
+// stands in for the blaseball feed and chronicler. serves tests/fixtures/feed.json and
+// tests/fixtures/game_updates.json
+pub struct MockUpstream {
+    pub port: u16,
+}
+
+impl MockUpstream {
+    pub fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{}", self.port, path)
+    }
+}
+
+struct MockData {
+    feed: JSONValue,
+    game_updates: JSONValue,
+}
+
+#[get("/feed")]
+fn mock_feed(data: &State<MockData>) -> Json<JSONValue> {
+    Json(data.feed.clone())
+}
+
+#[get("/library")]
+fn mock_library() -> Json<JSONValue> {
+    Json(json!([]))
+}
+
+#[get("/chronicler/games/updates?<game>&<page>")]
+fn mock_game_updates(
+    data: &State<MockData>,
+    game: Option<String>,
+    page: Option<String>,
+) -> Json<JSONValue> {
+    // everything fits on the first page
+    let updates = match page {
+        Some(_) => vec![],
+        None => data.game_updates["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|u| game.as_deref().map(|g| u["gameId"] == g).unwrap_or(true))
+            .cloned()
+            .collect(),
+    };
+
+    Json(json!({ "nextPage": null, "data": updates }))
+}
+
+// launches the mock on the runtime, where it keeps serving until the runtime is dropped
+pub fn launch_mock(rt: &Runtime) -> MockUpstream {
+    let port = free_port();
+    let figment = rocket::Config::figment()
+        .merge(("port", port))
+        .merge(("log_level", "off"))
+        .merge(("shutdown.ctrlc", false));
+
+    let mock = rocket::custom(figment)
+        .manage(MockData {
+            feed: read_fixture("feed.json"),
+            game_updates: read_fixture("game_updates.json"),
+        })
+        .mount("/", routes![mock_feed, mock_library, mock_game_updates]);
+    rt.spawn(mock.launch());
+
+    wait_for_port(port);
+    MockUpstream { port }
+}
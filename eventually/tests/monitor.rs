@@ -0,0 +1,120 @@
+#[macro_use]
+mod common;
+
+use common::*;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+const CHANGED: &str = "55555555-5555-4555-8555-555555555555";
+const NEW: &str = "66666666-6666-4666-8666-666666666666";
+const SPARSE: &str = "77777777-7777-4777-8777-777777777777";
+
+#[test]
+fn monitor_ingests_new_and_changed_events() {
+    let db = test_db!();
+    db.fill("events.ndjson");
+
+    let rt = runtime();
+    let mock = launch_mock(&rt);
+
+    let mut monitor = Command::new(env!("CARGO_BIN_EXE_monitor"))
+        .env("DB_URL", &db.url)
+        .env("FEED_URL", mock.url("/feed"))
+        .env("LIBRARY_URL", mock.url("/library"))
+        .env("POLL_DELAY", "200")
+        .env("LIBRARY_POLL_DELAY", "3600")
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut conn = db.connect();
+    let new_id = Uuid::parse_str(NEW).unwrap();
+    let started = Instant::now();
+    let ingested = loop {
+        let found = conn
+            .query_opt(
+                "SELECT 1 FROM documents_millis WHERE doc_id = $1",
+                &[&new_id],
+            )
+            .unwrap()
+            .is_some();
+        if found || started.elapsed() > Duration::from_secs(30) {
+            break found;
+        }
+        thread::sleep(Duration::from_millis(200));
+    };
+    // give it another poll, which shouldn't record the same change twice
+    thread::sleep(Duration::from_millis(600));
+    monitor.kill().unwrap();
+    monitor.wait().unwrap();
+    assert!(ingested, "monitor didn't ingest the new event");
+
+    let stored = conn
+        .query_one(
+            "SELECT object FROM documents_millis WHERE doc_id = $1",
+            &[&new_id],
+        )
+        .unwrap()
+        .get::<_, serde_json::Value>(0);
+    assert_eq!(stored["created"], 1615924860000i64);
+    assert_eq!(
+        stored["metadata"]["_eventually_ingest_source"],
+        "blaseball.com"
+    );
+
+    // an event missing most fields is still stored, with its nulls left as they were
+    let sparse = conn
+        .query_one(
+            "SELECT object FROM documents_millis WHERE doc_id = $1",
+            &[&Uuid::parse_str(SPARSE).unwrap()],
+        )
+        .unwrap()
+        .get::<_, serde_json::Value>(0);
+    assert_eq!(sparse["created"], 1615924830000i64);
+    assert!(sparse.get("season").is_none());
+    assert!(sparse["gameTags"].is_null());
+    assert!(sparse["nuts"].is_null());
+    assert_eq!(
+        sparse["metadata"]["_eventually_ingest_source"],
+        "blaseball.com"
+    );
+
+    // the new event pushed the end of its day back
+    let bounds = conn
+        .query_one(
+            "SELECT first_created, last_created FROM time_bounds
+            WHERE sim = 'thisidisstaticyo' AND season = 11 AND day = 6",
+            &[],
+        )
+        .unwrap();
+    assert_eq!(bounds.get::<_, i64>(0), 1615924800000);
+    assert_eq!(bounds.get::<_, i64>(1), 1615924860000);
+
+    rt.block_on(async {
+        let client = local_client(db.figment()).await;
+
+        let events = get_json(&client, "/events?type=106&sortorder=asc").await;
+        assert_eq!(events[0]["id"], CHANGED);
+        assert_eq!(
+            events[0]["description"],
+            "Sutton Dreamy gained +2 Charisma."
+        );
+
+        // the old and new copies of the changed event
+        let versions = get_json(&client, &format!("/versions?id={}", CHANGED)).await;
+        let descriptions: Vec<&str> = versions
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v["description"].as_str().unwrap())
+            .collect();
+        assert_eq!(descriptions.len(), 2);
+        assert!(descriptions.contains(&"Sutton Dreamy gained +1 Charisma."));
+        assert!(descriptions.contains(&"Sutton Dreamy gained +2 Charisma."));
+
+        let versions = get_json(&client, &format!("/versions?id={}", NEW)).await;
+        assert!(versions.as_array().unwrap().is_empty());
+    });
+}
@@ -0,0 +1,92 @@
+use chrono::{Duration, TimeZone, Utc};
+use rustventually::*;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+fn checked_in() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("time_map.json")
+}
+
+#[test]
+fn checked_in_time_map_is_valid() {
+    let time_map = read_time_map(&checked_in()).unwrap();
+    assert_eq!(time_map.len(), 11);
+    let problems: Vec<String> = validate_time_map(&time_map)
+        .iter()
+        .map(|p| p.to_string())
+        .collect();
+    assert!(problems.is_empty(), "{:?}", problems);
+}
+
+#[test]
+fn converts_between_formats() {
+    let original = std::fs::read_to_string(checked_in()).unwrap();
+    let dir = std::env::temp_dir().join(format!("eventually-time-map-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut time_map = read_time_map(&checked_in()).unwrap();
+    for name in ["time_map.bin", "time_map.yaml", "time_map.json"] {
+        let path = dir.join(name);
+        write_time_map(&path, &time_map).unwrap();
+        time_map = read_time_map(&path).unwrap();
+    }
+
+    // back where it started, byte for byte
+    assert_eq!(
+        std::fs::read_to_string(dir.join("time_map.json")).unwrap(),
+        original
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn validation_finds_every_problem() {
+    let start = Utc.ymd(2021, 3, 1).and_hms(16, 0, 0);
+    let hour = Duration::hours(1);
+    let time_map = vec![
+        TimeMapSeason {
+            lower_bound: start,
+            higher_bound: start + hour * 10,
+            days: vec![
+                (start, start + hour),
+                // overlaps day 0
+                (start + hour / 2, start + hour * 2),
+                // ends before it starts
+                (start + hour * 4, start + hour * 3),
+                // past the end of the season
+                (start + hour * 9, start + hour * 11),
+            ],
+        },
+        // starts before season 0 ends, and has no days
+        TimeMapSeason {
+            lower_bound: start + hour * 5,
+            higher_bound: start + hour * 20,
+            days: vec![],
+        },
+    ];
+
+    let problems: Vec<String> = validate_time_map(&time_map)
+        .iter()
+        .map(|p| p.to_string())
+        .collect();
+    assert_eq!(
+        problems,
+        vec![
+            "season 0, day 1 starts before the previous day ends",
+            "season 0, day 2 ends before it starts",
+            "season 0, day 3 isn't within its season's bounds",
+            "season 1 has no days",
+            "season 1 starts before the previous season ends",
+        ]
+    );
+}
+
+#[test]
+fn numbers_have_to_match_positions() {
+    let mut document = TimeMapDocument::from_time_map(&read_time_map(&checked_in()).unwrap());
+    document.seasons.remove(3);
+    assert!(matches!(
+        document.into_time_map(),
+        Err(TimeMapError::MissingSeason(3))
+    ));
+}